@@ -0,0 +1,278 @@
+use embedded_hal::i2c::I2c;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::fmt::Debug;
+use std::time::Duration;
+
+const POWER_ON: u8 = 0x01;
+const RESET: u8 = 0x07; // Clears the data register, only valid while powered on
+const MTREG_HIGH: u8 = 0x40; // 01000_MT[7:5]
+const MTREG_LOW: u8 = 0x60; // 011_MT[4:0]
+
+const MTREG_DEFAULT: u8 = 69;
+const MTREG_MIN: u8 = 31;
+const MTREG_MAX: u8 = 254;
+
+// Measurement modes from the datasheet, one-time modes power down after each reading
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Mode {
+    ContinuousHighRes,  // 1 lx resolution
+    ContinuousHighRes2, // 0.5 lx resolution
+    ContinuousLowRes,   // 4 lx resolution
+    OneTimeHighRes,
+    OneTimeHighRes2,
+    OneTimeLowRes,
+}
+
+impl Mode {
+    fn opcode(self) -> u8 {
+        match self {
+            Mode::ContinuousHighRes => 0x10,
+            Mode::ContinuousHighRes2 => 0x11,
+            Mode::ContinuousLowRes => 0x13,
+            Mode::OneTimeHighRes => 0x20,
+            Mode::OneTimeHighRes2 => 0x21,
+            Mode::OneTimeLowRes => 0x23,
+        }
+    }
+
+    fn is_one_time(self) -> bool {
+        matches!(self, Mode::OneTimeHighRes | Mode::OneTimeHighRes2 | Mode::OneTimeLowRes)
+    }
+
+    // Worst case measurement time at the default MTreg
+    fn max_measurement_ms(self) -> u32 {
+        match self {
+            Mode::ContinuousLowRes | Mode::OneTimeLowRes => 24,
+            _ => 180,
+        }
+    }
+
+    // High resolution mode 2 counts in half lux steps
+    fn counts_per_step(self) -> f32 {
+        match self {
+            Mode::ContinuousHighRes2 | Mode::OneTimeHighRes2 => 2.0,
+            _ => 1.0,
+        }
+    }
+}
+
+// Measurement time register, higher is more sensitive but slower. Checked when config.json
+// is loaded, so a bad value never reaches the sensor
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(try_from = "u8", into = "u8")]
+pub struct Mtreg(u8);
+
+impl Default for Mtreg {
+    fn default() -> Self {
+        Mtreg(MTREG_DEFAULT)
+    }
+}
+
+impl TryFrom<u8> for Mtreg {
+    type Error = String;
+
+    fn try_from(mtreg: u8) -> Result<Self, String> {
+        if (MTREG_MIN..=MTREG_MAX).contains(&mtreg) {
+            Ok(Mtreg(mtreg))
+        } else {
+            Err(format!("mtreg must be between {} and {}, got {}", MTREG_MIN, MTREG_MAX, mtreg))
+        }
+    }
+}
+
+impl From<Mtreg> for u8 {
+    fn from(mtreg: Mtreg) -> u8 {
+        mtreg.0
+    }
+}
+
+#[derive(Debug)]
+pub enum Error<E> {
+    I2c(E),
+}
+
+impl<E> From<E> for Error<E> {
+    fn from(e: E) -> Self {
+        Error::I2c(e)
+    }
+}
+
+pub struct BH1750<I2C> {
+    i2c: I2C,
+    address: u8,
+    mode: Mode,
+    mtreg: Mtreg,
+}
+
+impl<I2C, E> BH1750<I2C>
+where
+    I2C: I2c<Error = E>,
+    E: Debug,
+{
+    pub fn new(i2c: I2C, address: u8) -> Self {
+        BH1750 {
+            i2c,
+            address,
+            mode: Mode::ContinuousHighRes,
+            mtreg: Mtreg::default(),
+        }
+    }
+
+    pub fn begin(&mut self, mode: Mode, mtreg: Mtreg) -> Result<(), Error<E>> {
+        info!("Initializing BH1750 sensor");
+
+        self.send(POWER_ON)?;
+        self.send(RESET)?;
+        self.mode = mode;
+        self.set_mtreg(mtreg)?; // Also starts measuring in the continuous modes
+
+        info!("BH1750 sensor initialized in {:?} mode with MTreg {}", mode, mtreg.0);
+        Ok(())
+    }
+
+    // Change the measurement time register, higher values are more sensitive but slower
+    pub fn set_mtreg(&mut self, mtreg: Mtreg) -> Result<(), Error<E>> {
+        self.send(MTREG_HIGH | (mtreg.0 >> 5))?;
+        self.send(MTREG_LOW | (mtreg.0 & 0x1F))?;
+        self.mtreg = mtreg;
+
+        // The new MTreg only applies once measurement is restarted
+        if !self.mode.is_one_time() {
+            self.send(self.mode.opcode())?;
+            std::thread::sleep(self.measurement_time());
+        }
+        Ok(())
+    }
+
+    pub fn read_raw(&mut self) -> Result<u16, Error<E>> {
+        if self.mode.is_one_time() {
+            // One-time modes power down afterwards, so power on and trigger every time
+            self.send(POWER_ON)?;
+            self.send(self.mode.opcode())?;
+            std::thread::sleep(self.measurement_time());
+        }
+
+        let mut buf = [0; 2];
+        self.i2c.read(self.address, &mut buf)?;
+        Ok(u16::from_be_bytes(buf))
+    }
+
+    pub fn read_lux(&mut self) -> Result<f32, Error<E>> {
+        let raw = self.read_raw()?;
+        Ok(self.raw_to_lux(raw))
+    }
+
+    // lux = counts / 1.2 scaled by MTreg, halved again in high resolution mode 2
+    pub fn raw_to_lux(&self, raw: u16) -> f32 {
+        raw as f32 / 1.2 * (MTREG_DEFAULT as f32 / self.mtreg.0 as f32) / self.mode.counts_per_step()
+    }
+
+    fn measurement_time(&self) -> Duration {
+        let ms = self.mode.max_measurement_ms() * self.mtreg.0 as u32 / MTREG_DEFAULT as u32;
+        Duration::from_millis(ms as u64 + 1)
+    }
+
+    fn send(&mut self, opcode: u8) -> Result<(), E> {
+        self.i2c.write(self.address, &[opcode])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_hal::i2c::{ErrorType, Operation};
+    use std::convert::Infallible;
+
+    const ADDRESS: u8 = 0x23;
+
+    // Logs the opcodes sent and answers reads with a fixed count
+    struct FakeBus {
+        opcodes: Vec<u8>,
+        count: u16,
+    }
+
+    impl ErrorType for FakeBus {
+        type Error = Infallible;
+    }
+
+    impl I2c for FakeBus {
+        fn transaction(&mut self, address: u8, operations: &mut [Operation<'_>]) -> Result<(), Infallible> {
+            assert_eq!(address, ADDRESS);
+            for operation in operations {
+                match operation {
+                    Operation::Write(bytes) => self.opcodes.extend_from_slice(bytes),
+                    Operation::Read(buffer) => buffer.copy_from_slice(&self.count.to_be_bytes()),
+                }
+            }
+            Ok(())
+        }
+    }
+
+    fn bh1750(mode: Mode, mtreg: u8) -> BH1750<FakeBus> {
+        let mut bh1750 = BH1750::new(FakeBus { opcodes: Vec::new(), count: 0 }, ADDRESS);
+        bh1750.mode = mode;
+        bh1750.mtreg = Mtreg(mtreg);
+        bh1750
+    }
+
+    fn assert_lux(actual: f32, expected: f32) {
+        assert!((actual - expected).abs() < 0.01, "{} lx, expected {}", actual, expected);
+    }
+
+    #[test]
+    fn lux_from_counts() {
+        // 1.2 counts per lux at the default MTreg
+        assert_lux(bh1750(Mode::ContinuousHighRes, 69).raw_to_lux(1200), 1000.0);
+        assert_lux(bh1750(Mode::ContinuousLowRes, 69).raw_to_lux(1200), 1000.0);
+        // Half lux steps in high resolution mode 2
+        assert_lux(bh1750(Mode::OneTimeHighRes2, 69).raw_to_lux(1200), 500.0);
+        // Doubling MTreg doubles the counts for the same light
+        assert_lux(bh1750(Mode::ContinuousHighRes, 138).raw_to_lux(1200), 500.0);
+        assert_lux(bh1750(Mode::ContinuousHighRes, 31).raw_to_lux(1200), 2225.81);
+    }
+
+    #[test]
+    fn read_lux_converts_the_big_endian_count() {
+        let mut bh1750 = bh1750(Mode::ContinuousHighRes, 69);
+        bh1750.i2c.count = 0x04B0; // 1200
+        assert_lux(bh1750.read_lux().unwrap(), 1000.0);
+    }
+
+    #[test]
+    fn mtreg_is_split_across_two_opcodes() {
+        // One-time modes don't restart measuring, so only the MTreg opcodes are sent
+        let cases = [(69, [0x42, 0x65]), (31, [0x40, 0x7F]), (254, [0x47, 0x7E]), (128, [0x44, 0x60])];
+        for (mtreg, opcodes) in cases {
+            let mut bh1750 = bh1750(Mode::OneTimeHighRes, 69);
+            bh1750.set_mtreg(Mtreg(mtreg)).unwrap();
+            assert_eq!(bh1750.i2c.opcodes, opcodes, "MTreg {}", mtreg);
+        }
+    }
+
+    #[test]
+    fn new_mtreg_restarts_continuous_measurement() {
+        let mut bh1750 = bh1750(Mode::ContinuousLowRes, 69);
+        bh1750.set_mtreg(Mtreg(31)).unwrap();
+        assert_eq!(bh1750.i2c.opcodes, [0x40, 0x7F, 0x13]);
+    }
+
+    #[test]
+    fn measurement_time_scales_with_mtreg() {
+        assert_eq!(bh1750(Mode::ContinuousHighRes, 69).measurement_time(), Duration::from_millis(181));
+        assert_eq!(bh1750(Mode::ContinuousHighRes, 138).measurement_time(), Duration::from_millis(361));
+        assert_eq!(bh1750(Mode::OneTimeLowRes, 69).measurement_time(), Duration::from_millis(25));
+        assert_eq!(bh1750(Mode::OneTimeHighRes2, 254).measurement_time(), Duration::from_millis(663));
+        assert_eq!(bh1750(Mode::ContinuousLowRes, 31).measurement_time(), Duration::from_millis(11));
+    }
+
+    #[test]
+    fn mtreg_range() {
+        assert_eq!(Mtreg::try_from(31), Ok(Mtreg(31)));
+        assert_eq!(Mtreg::try_from(254), Ok(Mtreg(254)));
+        assert!(Mtreg::try_from(30).is_err());
+        assert!(Mtreg::try_from(255).is_err());
+        assert_eq!(Mtreg::default(), Mtreg(69));
+    }
+}
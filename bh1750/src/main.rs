@@ -1,3 +1,118 @@
-fn main() {
-    println!("Hello, world!");
+mod bh1750;
+
+use linux_embedded_hal::I2cdev;  // Import I2C device from linux_embedded_hal
+use serde::{Deserialize, Serialize}; // Import serialization/deserialization from Serde
+use sensor_core::{Sensor, SensorConfig, SensorError, ServerConfig}; // Import shared sensor server
+use bh1750::{Mode, Mtreg, BH1750}; // Import BH1750 driver
+
+// Configuration structure for the application
+#[derive(Serialize, Deserialize, Clone)]
+struct Config {
+    #[serde(flatten)]
+    server: ServerConfig, // Port, bind address and I2C bus path
+    i2c_address_decimal: u16, // I2C address of the BH1750 sensor
+    #[serde(default = "default_mode")]
+    mode: Mode, // Measurement mode, e.g. continuous_high_res or one_time_low_res
+    #[serde(default)]
+    mtreg: Mtreg, // Measurement time register (31-254), higher is more sensitive
+}
+
+fn default_mode() -> Mode {
+    Mode::ContinuousHighRes // 1 lx resolution
+}
+
+// Default implementation for the Config struct
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            server: ServerConfig::with_port(5005), // Default network port
+            i2c_address_decimal: 0x23, // Default I2C address (35 in decimal), 0x5C with ADDR high
+            mode: default_mode(),
+            mtreg: Mtreg::default(), // Datasheet default sensitivity, 69
+        }
+    }
+}
+
+impl SensorConfig for Config {
+    fn server(&self) -> &ServerConfig {
+        &self.server
+    }
+
+    fn i2c_address(&self) -> Option<u16> {
+        Some(self.i2c_address_decimal)
+    }
+}
+
+// Structure to hold sensor data
+#[derive(Serialize)]
+struct SensorData {
+    lux: f32,
+}
+
+struct Bh1750Sensor {
+    bh1750: BH1750<I2cdev>,
+}
+
+impl Sensor for Bh1750Sensor {
+    type Config = Config;
+    type Reading = SensorData;
+
+    const MODEL: &'static str = "BH1750";
+
+    fn init(config: &Config) -> Result<Self, SensorError> {
+        // Set up the I2C bus and BH1750 sensor
+        let i2c_bus = I2cdev::new(&config.server.i2c_bus_device_path).map_err(SensorError::bus)?;
+
+        // Create BH1750 sensor object with the correct I2C address
+        let mut bh1750 = BH1750::new(i2c_bus, config.i2c_address_decimal as u8);
+
+        // Power on and apply the configured mode and sensitivity
+        bh1750.begin(config.mode, config.mtreg)?;
+
+        Ok(Bh1750Sensor { bh1750 })
+    }
+
+    fn read(&mut self) -> Result<SensorData, SensorError> {
+        let lux = self.bh1750.read_lux()?;
+
+        Ok(SensorData { lux })
+    }
+}
+
+// Sort driver errors into the shared error codes
+impl<E: embedded_hal::i2c::Error> From<bh1750::Error<E>> for SensorError {
+    fn from(e: bh1750::Error<E>) -> Self {
+        match e {
+            bh1750::Error::I2c(e) => SensorError::i2c(e),
+        }
+    }
+}
+
+fn main() -> std::io::Result<()> {
+    sensor_core::run::<Bh1750Sensor>()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SERVER: &str = r#""network_port": 5005, "bind_address": "0.0.0.0", "i2c_bus_device_path": "/dev/i2c-1""#;
+
+    fn config(fields: &str) -> Result<Config, serde_json::Error> {
+        serde_json::from_str(&format!("{{{}, {}}}", SERVER, fields))
+    }
+
+    #[test]
+    fn mode_and_mtreg_default() {
+        let config = config(r#""i2c_address_decimal": 35"#).unwrap();
+        assert_eq!(config.mode, Mode::ContinuousHighRes);
+        assert_eq!(config.mtreg, Mtreg::default());
+    }
+
+    #[test]
+    fn out_of_range_mtreg_is_rejected_when_loading() {
+        let error = config(r#""i2c_address_decimal": 35, "mtreg": 20"#).err().unwrap().to_string();
+        assert!(error.contains("mtreg must be between 31 and 254, got 20"), "{}", error);
+        assert!(config(r#""i2c_address_decimal": 35, "mtreg": 31"#).is_ok());
+    }
 }
@@ -0,0 +1,91 @@
+// Conversions between station pressure, sea level pressure and altitude, pressures in hPa
+
+// Exponent of the International Standard Atmosphere's barometric formula
+const ISA_EXPONENT: f32 = 5.255;
+
+// Height at which the ISA pressure would reach zero, in metres
+const ISA_HEIGHT: f32 = 44330.0;
+
+// ISA temperature lapse rate, °C per metre
+const LAPSE_RATE: f32 = 0.0065;
+
+// Altitude of the sensor given the current sea level pressure (QNH)
+pub fn altitude(pressure: f32, sea_level_pressure: f32) -> f32 {
+    ISA_HEIGHT * (1.0 - (pressure / sea_level_pressure).powf(1.0 / ISA_EXPONENT))
+}
+
+// QNH, reduced to sea level through the standard atmosphere. What altimeters and METARs use
+pub fn qnh(pressure: f32, station_altitude: f32) -> f32 {
+    pressure / (1.0 - station_altitude / ISA_HEIGHT).powf(ISA_EXPONENT)
+}
+
+// QFF, reduced to sea level using the measured temperature. What weather maps use
+pub fn qff(pressure: f32, station_altitude: f32, temperature: f32) -> f32 {
+    let h = LAPSE_RATE * station_altitude;
+    pressure * (1.0 - h / (temperature + h + 273.15)).powf(-ISA_EXPONENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ISA_SEA_LEVEL: f32 = 1013.25;
+
+    // Standard atmosphere pressures, (altitude in metres, hPa)
+    const ISA_TABLE: [(f32, f32); 4] = [(500.0, 954.61), (1000.0, 898.75), (2000.0, 794.95), (3000.0, 701.09)];
+
+    fn assert_close(actual: f32, expected: f32, tolerance: f32) {
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "{} is not within {} of {}",
+            actual,
+            tolerance,
+            expected
+        );
+    }
+
+    #[test]
+    fn altitude_matches_the_standard_atmosphere() {
+        assert_close(altitude(ISA_SEA_LEVEL, ISA_SEA_LEVEL), 0.0, 0.01);
+        for (height, pressure) in ISA_TABLE {
+            assert_close(altitude(pressure, ISA_SEA_LEVEL), height, 2.0);
+        }
+    }
+
+    #[test]
+    fn qnh_of_a_standard_atmosphere_is_1013_25() {
+        assert_close(qnh(ISA_SEA_LEVEL, 0.0), ISA_SEA_LEVEL, 0.001);
+        for (height, pressure) in ISA_TABLE {
+            assert_close(qnh(pressure, height), ISA_SEA_LEVEL, 0.2);
+        }
+    }
+
+    #[test]
+    fn qnh_and_altitude_round_trip() {
+        for station_altitude in [-100.0, 0.0, 250.0, 1500.0, 4000.0] {
+            for pressure in [600.0, 850.0, 1000.0] {
+                let sea_level = qnh(pressure, station_altitude);
+                assert_close(altitude(pressure, sea_level), station_altitude, 0.5);
+            }
+        }
+    }
+
+    #[test]
+    fn qff_equals_qnh_at_the_standard_temperature() {
+        // ISA temperature falls from 15 °C by 6.5 °C per km
+        for (height, pressure) in ISA_TABLE {
+            let temperature = 15.0 - LAPSE_RATE * height;
+            assert_close(qff(pressure, height, temperature), qnh(pressure, height), 0.2);
+        }
+    }
+
+    #[test]
+    fn qff_follows_the_measured_temperature() {
+        // Warmer air is less dense, so less pressure is added on the way down
+        let standard = qff(898.75, 1000.0, 8.5);
+        assert!(qff(898.75, 1000.0, 30.0) < standard);
+        assert!(qff(898.75, 1000.0, -10.0) > standard);
+        // At sea level there's nothing to reduce
+        assert_close(qff(1005.0, 0.0, 30.0), 1005.0, 0.001);
+    }
+}
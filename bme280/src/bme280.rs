@@ -0,0 +1,757 @@
+use embedded_hal::i2c::I2c;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::fmt::Debug;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+const REG_CALIB_TP: u8 = 0x88; // dig_T1 to dig_H1, 26 bytes
+const REG_CHIP_ID: u8 = 0xD0;
+const REG_RESET: u8 = 0xE0;
+const REG_CALIB_H: u8 = 0xE1; // dig_H2 to dig_H6, 7 bytes
+const REG_CTRL_HUM: u8 = 0xF2;
+const REG_STATUS: u8 = 0xF3;
+const REG_CTRL_MEAS: u8 = 0xF4;
+const REG_CONFIG: u8 = 0xF5;
+const REG_DATA: u8 = 0xF7; // press_msb to hum_lsb, 8 bytes
+
+const CHIP_ID: u8 = 0x60;
+const SOFT_RESET: u8 = 0xB6;
+const STATUS_MEASURING: u8 = 0x08;
+const STATUS_IM_UPDATE: u8 = 0x01; // Calibration data is being copied from NVM
+const NVM_COPY_TIMEOUT: Duration = Duration::from_millis(50); // Takes a few ms, a stuck bit means a bad chip
+
+const MODE_SLEEP: u8 = 0b00;
+const MODE_FORCED: u8 = 0b01;
+const MODE_NORMAL: u8 = 0b11;
+
+// Oversampling per measurement, stored in config.json as the sample count, 0 skips it
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(try_from = "u8", into = "u8")]
+pub enum Oversampling {
+    Skip,
+    X1,
+    X2,
+    X4,
+    X8,
+    X16,
+}
+
+impl Oversampling {
+    fn bits(self) -> u8 {
+        match self {
+            Oversampling::Skip => 0b000,
+            Oversampling::X1 => 0b001,
+            Oversampling::X2 => 0b010,
+            Oversampling::X4 => 0b011,
+            Oversampling::X8 => 0b100,
+            Oversampling::X16 => 0b101,
+        }
+    }
+
+    fn from_bits(bits: u8) -> Self {
+        match bits & 0b111 {
+            0b000 => Oversampling::Skip,
+            0b001 => Oversampling::X1,
+            0b010 => Oversampling::X2,
+            0b011 => Oversampling::X4,
+            0b100 => Oversampling::X8,
+            _ => Oversampling::X16, // The remaining codes all mean 16
+        }
+    }
+}
+
+impl TryFrom<u8> for Oversampling {
+    type Error = String;
+
+    fn try_from(samples: u8) -> Result<Self, String> {
+        match samples {
+            0 => Ok(Oversampling::Skip),
+            1 => Ok(Oversampling::X1),
+            2 => Ok(Oversampling::X2),
+            4 => Ok(Oversampling::X4),
+            8 => Ok(Oversampling::X8),
+            16 => Ok(Oversampling::X16),
+            _ => Err(format!("oversampling must be 0, 1, 2, 4, 8 or 16, got {}", samples)),
+        }
+    }
+}
+
+impl From<Oversampling> for u8 {
+    fn from(oversampling: Oversampling) -> u8 {
+        match oversampling {
+            Oversampling::Skip => 0,
+            Oversampling::X1 => 1,
+            Oversampling::X2 => 2,
+            Oversampling::X4 => 4,
+            Oversampling::X8 => 8,
+            Oversampling::X16 => 16,
+        }
+    }
+}
+
+// IIR filter on temperature and pressure, stored in config.json as the coefficient, 0 is off
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(try_from = "u8", into = "u8")]
+pub enum Filter {
+    Off,
+    X2,
+    X4,
+    X8,
+    X16,
+}
+
+impl Filter {
+    fn bits(self) -> u8 {
+        match self {
+            Filter::Off => 0b000,
+            Filter::X2 => 0b001,
+            Filter::X4 => 0b010,
+            Filter::X8 => 0b011,
+            Filter::X16 => 0b100,
+        }
+    }
+
+    fn from_bits(bits: u8) -> Self {
+        match bits & 0b111 {
+            0b000 => Filter::Off,
+            0b001 => Filter::X2,
+            0b010 => Filter::X4,
+            0b011 => Filter::X8,
+            _ => Filter::X16,
+        }
+    }
+}
+
+impl TryFrom<u8> for Filter {
+    type Error = String;
+
+    fn try_from(coefficient: u8) -> Result<Self, String> {
+        match coefficient {
+            0 => Ok(Filter::Off),
+            2 => Ok(Filter::X2),
+            4 => Ok(Filter::X4),
+            8 => Ok(Filter::X8),
+            16 => Ok(Filter::X16),
+            _ => Err(format!("iir_filter must be 0, 2, 4, 8 or 16, got {}", coefficient)),
+        }
+    }
+}
+
+impl From<Filter> for u8 {
+    fn from(filter: Filter) -> u8 {
+        match filter {
+            Filter::Off => 0,
+            Filter::X2 => 2,
+            Filter::X4 => 4,
+            Filter::X8 => 8,
+            Filter::X16 => 16,
+        }
+    }
+}
+
+// Inactive time between measurements in normal mode, stored in config.json in milliseconds
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(try_from = "f32", into = "f32")]
+pub enum Standby {
+    Ms0_5,
+    Ms10,
+    Ms20,
+    Ms62_5,
+    Ms125,
+    Ms250,
+    Ms500,
+    Ms1000,
+}
+
+impl Standby {
+    fn bits(self) -> u8 {
+        match self {
+            Standby::Ms0_5 => 0b000,
+            Standby::Ms62_5 => 0b001,
+            Standby::Ms125 => 0b010,
+            Standby::Ms250 => 0b011,
+            Standby::Ms500 => 0b100,
+            Standby::Ms1000 => 0b101,
+            Standby::Ms10 => 0b110,
+            Standby::Ms20 => 0b111,
+        }
+    }
+
+    fn from_bits(bits: u8) -> Self {
+        match bits & 0b111 {
+            0b000 => Standby::Ms0_5,
+            0b001 => Standby::Ms62_5,
+            0b010 => Standby::Ms125,
+            0b011 => Standby::Ms250,
+            0b100 => Standby::Ms500,
+            0b101 => Standby::Ms1000,
+            0b110 => Standby::Ms10,
+            _ => Standby::Ms20,
+        }
+    }
+}
+
+impl TryFrom<f32> for Standby {
+    type Error = String;
+
+    fn try_from(ms: f32) -> Result<Self, String> {
+        [
+            Standby::Ms0_5,
+            Standby::Ms10,
+            Standby::Ms20,
+            Standby::Ms62_5,
+            Standby::Ms125,
+            Standby::Ms250,
+            Standby::Ms500,
+            Standby::Ms1000,
+        ]
+        .into_iter()
+        .find(|&standby| f32::from(standby) == ms)
+        .ok_or_else(|| format!("standby_ms must be 0.5, 10, 20, 62.5, 125, 250, 500 or 1000, got {}", ms))
+    }
+}
+
+impl From<Standby> for f32 {
+    fn from(standby: Standby) -> f32 {
+        match standby {
+            Standby::Ms0_5 => 0.5,
+            Standby::Ms10 => 10.0,
+            Standby::Ms20 => 20.0,
+            Standby::Ms62_5 => 62.5,
+            Standby::Ms125 => 125.0,
+            Standby::Ms250 => 250.0,
+            Standby::Ms500 => 500.0,
+            Standby::Ms1000 => 1000.0,
+        }
+    }
+}
+
+// Normal mode measures continuously with standby in between, forced mode measures once per read
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Mode {
+    Forced,
+    Normal,
+}
+
+// Everything that controls how the BME280 measures
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct Settings {
+    #[serde(default = "default_mode")]
+    pub mode: Mode,
+    #[serde(default = "default_oversampling")]
+    pub temperature_oversampling: Oversampling,
+    #[serde(default = "default_oversampling")]
+    pub pressure_oversampling: Oversampling,
+    #[serde(default = "default_oversampling")]
+    pub humidity_oversampling: Oversampling,
+    #[serde(default = "default_filter")]
+    pub iir_filter: Filter,
+    #[serde(default = "default_standby", rename = "standby_ms")]
+    pub standby: Standby, // Only used in normal mode
+}
+
+fn default_mode() -> Mode {
+    Mode::Forced
+}
+
+fn default_oversampling() -> Oversampling {
+    Oversampling::X1
+}
+
+fn default_filter() -> Filter {
+    Filter::Off
+}
+
+fn default_standby() -> Standby {
+    Standby::Ms1000
+}
+
+// The datasheet's "weather monitoring" setup, lowest power and noise is fine at 1 reading a minute
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            mode: default_mode(),
+            temperature_oversampling: default_oversampling(),
+            pressure_oversampling: default_oversampling(),
+            humidity_oversampling: default_oversampling(),
+            iir_filter: default_filter(),
+            standby: default_standby(),
+        }
+    }
+}
+
+impl Settings {
+    // Maximum measurement time from the datasheet, 2.3 ms per sample plus fixed overheads
+    fn measurement_time(&self) -> Duration {
+        let mut us = 1250;
+        for (oversampling, overhead) in [
+            (self.temperature_oversampling, 0),
+            (self.pressure_oversampling, 575),
+            (self.humidity_oversampling, 575),
+        ] {
+            if oversampling != Oversampling::Skip {
+                us += 2300 * u8::from(oversampling) as u64 + overhead;
+            }
+        }
+        Duration::from_micros(us)
+    }
+}
+
+#[derive(Debug)]
+pub enum Error<E> {
+    I2c(E),
+    WrongDevice, // Chip ID did not match the BME280
+    NotReady,    // Measurement didn't finish in time
+}
+
+impl<E> From<E> for Error<E> {
+    fn from(e: E) -> Self {
+        Error::I2c(e)
+    }
+}
+
+// Temperature in °C, pressure in Pa and relative humidity in %
+pub struct Measurement {
+    pub temperature: f32,
+    pub pressure: f32,
+    pub humidity: f32,
+}
+
+// Factory trimming values, used to compensate the raw readings
+struct Calibration {
+    t1: f64,
+    t2: f64,
+    t3: f64,
+    p1: f64,
+    p2: f64,
+    p3: f64,
+    p4: f64,
+    p5: f64,
+    p6: f64,
+    p7: f64,
+    p8: f64,
+    p9: f64,
+    h1: f64,
+    h2: f64,
+    h3: f64,
+    h4: f64,
+    h5: f64,
+    h6: f64,
+}
+
+impl Calibration {
+    fn parse(tp: &[u8; 26], h: &[u8; 7]) -> Self {
+        let u = |i: usize| u16::from_le_bytes([tp[i], tp[i + 1]]) as f64;
+        let s = |i: usize| i16::from_le_bytes([tp[i], tp[i + 1]]) as f64;
+
+        Calibration {
+            t1: u(0),
+            t2: s(2),
+            t3: s(4),
+            p1: u(6),
+            p2: s(8),
+            p3: s(10),
+            p4: s(12),
+            p5: s(14),
+            p6: s(16),
+            p7: s(18),
+            p8: s(20),
+            p9: s(22),
+            h1: tp[25] as f64,
+            h2: i16::from_le_bytes([h[0], h[1]]) as f64,
+            h3: h[2] as f64,
+            // dig_H4 and dig_H5 are 12 bit values sharing the nibbles of 0xE5
+            h4: ((h[3] as i8 as i16) << 4 | (h[4] & 0x0F) as i16) as f64,
+            h5: ((h[5] as i8 as i16) << 4 | (h[4] >> 4) as i16) as f64,
+            h6: h[6] as i8 as f64,
+        }
+    }
+
+    // Floating point compensation from section 8.1 of the datasheet
+    fn compensate(&self, adc_t: u32, adc_p: u32, adc_h: u32) -> Measurement {
+        let (adc_t, adc_p, adc_h) = (adc_t as f64, adc_p as f64, adc_h as f64);
+
+        let var1 = (adc_t / 16384.0 - self.t1 / 1024.0) * self.t2;
+        let var2 = (adc_t / 131072.0 - self.t1 / 8192.0).powi(2) * self.t3;
+        let t_fine = var1 + var2;
+        let temperature = t_fine / 5120.0;
+
+        let mut var1 = t_fine / 2.0 - 64000.0;
+        let mut var2 = var1 * var1 * self.p6 / 32768.0;
+        var2 += var1 * self.p5 * 2.0;
+        var2 = var2 / 4.0 + self.p4 * 65536.0;
+        var1 = (self.p3 * var1 * var1 / 524288.0 + self.p2 * var1) / 524288.0;
+        var1 = (1.0 + var1 / 32768.0) * self.p1;
+        let pressure = if var1 == 0.0 {
+            0.0 // Avoid dividing by zero
+        } else {
+            let p = (1048576.0 - adc_p - var2 / 4096.0) * 6250.0 / var1;
+            let var1 = self.p9 * p * p / 2147483648.0;
+            let var2 = p * self.p8 / 32768.0;
+            p + (var1 + var2 + self.p7) / 16.0
+        };
+
+        let h = t_fine - 76800.0;
+        let h = (adc_h - (self.h4 * 64.0 + self.h5 / 16384.0 * h))
+            * (self.h2 / 65536.0 * (1.0 + self.h6 / 67108864.0 * h * (1.0 + self.h3 / 67108864.0 * h)));
+        let humidity = (h * (1.0 - self.h1 * h / 524288.0)).clamp(0.0, 100.0);
+
+        Measurement {
+            temperature: temperature as f32,
+            pressure: pressure as f32,
+            humidity: humidity as f32,
+        }
+    }
+}
+
+pub struct BME280<I2C> {
+    i2c: I2C,
+    address: u8,
+    calibration: Option<Calibration>,
+    settings: Settings,
+}
+
+impl<I2C, E> BME280<I2C>
+where
+    I2C: I2c<Error = E>,
+    E: Debug,
+{
+    pub fn new(i2c: I2C, address: u8) -> Self {
+        BME280 {
+            i2c,
+            address,
+            calibration: None,
+            settings: Settings::default(),
+        }
+    }
+
+    // Reset the sensor, load its calibration and apply the settings
+    pub fn begin(&mut self, settings: Settings) -> Result<(), Error<E>> {
+        info!("Initializing BME280 sensor");
+
+        let chip_id = self.read_register(REG_CHIP_ID)?;
+        if chip_id != CHIP_ID {
+            error!("BME280 chip ID mismatch: expected {:#04x}, got {:#04x}", CHIP_ID, chip_id);
+            return Err(Error::WrongDevice);
+        }
+
+        self.write_register(REG_RESET, SOFT_RESET)?;
+        sleep(Duration::from_millis(2)); // Start-up time after reset
+        let deadline = Instant::now() + NVM_COPY_TIMEOUT;
+        while self.read_register(REG_STATUS)? & STATUS_IM_UPDATE != 0 {
+            if Instant::now() > deadline {
+                return Err(Error::NotReady);
+            }
+            sleep(Duration::from_millis(1));
+        }
+
+        self.load_calibration()?;
+        self.configure(settings)?;
+
+        info!("BME280 sensor initialized with {:?}", settings);
+        Ok(())
+    }
+
+    // Use a sensor another service has set up, without resetting it or changing its settings
+    pub fn attach(&mut self) -> Result<(), Error<E>> {
+        let chip_id = self.read_register(REG_CHIP_ID)?;
+        if chip_id != CHIP_ID {
+            return Err(Error::WrongDevice);
+        }
+
+        self.load_calibration()?;
+        self.settings = self.settings()?;
+        Ok(())
+    }
+
+    fn load_calibration(&mut self) -> Result<(), Error<E>> {
+        let mut tp = [0; 26];
+        let mut h = [0; 7];
+        self.i2c.write_read(self.address, &[REG_CALIB_TP], &mut tp)?;
+        self.i2c.write_read(self.address, &[REG_CALIB_H], &mut h)?;
+        self.calibration = Some(Calibration::parse(&tp, &h));
+        Ok(())
+    }
+
+    // Apply new settings, the config register is only reliably written in sleep mode
+    pub fn configure(&mut self, settings: Settings) -> Result<(), Error<E>> {
+        self.write_register(REG_CTRL_MEAS, MODE_SLEEP)?;
+        self.write_register(REG_CONFIG, settings.standby.bits() << 5 | settings.iir_filter.bits() << 2)?;
+        // ctrl_hum only takes effect after the following ctrl_meas write
+        self.write_register(REG_CTRL_HUM, settings.humidity_oversampling.bits())?;
+        self.settings = settings;
+
+        if settings.mode == Mode::Normal {
+            self.write_register(REG_CTRL_MEAS, self.ctrl_meas(MODE_NORMAL))?;
+            sleep(settings.measurement_time()); // Let the first measurement finish
+        } else {
+            self.write_register(REG_CTRL_MEAS, self.ctrl_meas(MODE_SLEEP))?;
+        }
+        Ok(())
+    }
+
+    // The settings as currently held in the sensor's registers
+    pub fn settings(&mut self) -> Result<Settings, Error<E>> {
+        let ctrl_hum = self.read_register(REG_CTRL_HUM)?;
+        let ctrl_meas = self.read_register(REG_CTRL_MEAS)?;
+        let config = self.read_register(REG_CONFIG)?;
+
+        Ok(Settings {
+            // The sensor drops back to sleep after each forced measurement
+            mode: if ctrl_meas & 0b11 == MODE_NORMAL { Mode::Normal } else { Mode::Forced },
+            temperature_oversampling: Oversampling::from_bits(ctrl_meas >> 5),
+            pressure_oversampling: Oversampling::from_bits(ctrl_meas >> 2),
+            humidity_oversampling: Oversampling::from_bits(ctrl_hum),
+            iir_filter: Filter::from_bits(config >> 2),
+            standby: Standby::from_bits(config >> 5),
+        })
+    }
+
+    pub fn measure(&mut self) -> Result<Measurement, Error<E>> {
+        if self.settings.mode == Mode::Forced {
+            self.write_register(REG_CTRL_MEAS, self.ctrl_meas(MODE_FORCED))?;
+            self.wait_for_measurement()?;
+        }
+        self.read_measurement()
+    }
+
+    // Measure an attached sensor with the settings its owner gave it. In normal mode the data
+    // registers are kept fresh, otherwise trigger a forced measurement, which only sets the mode
+    // bits and leaves the oversampling, filter and standby settings as they were.
+    pub fn measure_shared(&mut self) -> Result<Measurement, Error<E>> {
+        let mut settings = self.settings()?;
+        if settings.mode != Mode::Normal {
+            // A sensor nobody has set up skips every measurement
+            for oversampling in [&mut settings.temperature_oversampling, &mut settings.pressure_oversampling] {
+                if *oversampling == Oversampling::Skip {
+                    *oversampling = Oversampling::X1;
+                }
+            }
+            self.settings = settings;
+            self.write_register(REG_CTRL_MEAS, self.ctrl_meas(MODE_FORCED))?;
+            self.wait_for_measurement()?;
+        }
+        self.read_measurement()
+    }
+
+    fn read_measurement(&mut self) -> Result<Measurement, Error<E>> {
+        // Burst read so all three values come from the same measurement
+        let mut data = [0; 8];
+        self.i2c.write_read(self.address, &[REG_DATA], &mut data)?;
+        let adc_p = (data[0] as u32) << 12 | (data[1] as u32) << 4 | (data[2] as u32) >> 4;
+        let adc_t = (data[3] as u32) << 12 | (data[4] as u32) << 4 | (data[5] as u32) >> 4;
+        let adc_h = (data[6] as u32) << 8 | data[7] as u32;
+
+        match &self.calibration {
+            Some(calibration) => Ok(calibration.compensate(adc_t, adc_p, adc_h)),
+            None => Err(Error::NotReady), // begin() hasn't been called
+        }
+    }
+
+    fn wait_for_measurement(&mut self) -> Result<(), Error<E>> {
+        let measurement_time = self.settings.measurement_time();
+        sleep(measurement_time);
+
+        let deadline = Instant::now() + measurement_time * 2;
+        while self.read_register(REG_STATUS)? & STATUS_MEASURING != 0 {
+            if Instant::now() > deadline {
+                return Err(Error::NotReady);
+            }
+            sleep(Duration::from_millis(1));
+        }
+        Ok(())
+    }
+
+    fn ctrl_meas(&self, mode: u8) -> u8 {
+        self.settings.temperature_oversampling.bits() << 5 | self.settings.pressure_oversampling.bits() << 2 | mode
+    }
+
+    fn read_register(&mut self, register: u8) -> Result<u8, E> {
+        let mut buf = [0];
+        self.i2c.write_read(self.address, &[register], &mut buf)?;
+        Ok(buf[0])
+    }
+
+    fn write_register(&mut self, register: u8, value: u8) -> Result<(), E> {
+        self.i2c.write(self.address, &[register, value])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_hal::i2c::{ErrorType, Operation};
+    use std::convert::Infallible;
+
+    const ADDRESS: u8 = 0x76;
+
+    // Stands in for the chip, a register map with auto-incrementing reads that logs every write
+    struct FakeBus {
+        registers: [u8; 256],
+        pointer: usize,
+        writes: Vec<(u8, u8)>,
+    }
+
+    impl FakeBus {
+        fn new(ctrl_hum: u8, ctrl_meas: u8, config: u8) -> Self {
+            let mut registers = [0; 256];
+            registers[REG_CHIP_ID as usize] = CHIP_ID;
+            registers[REG_CTRL_HUM as usize] = ctrl_hum;
+            registers[REG_CTRL_MEAS as usize] = ctrl_meas;
+            registers[REG_CONFIG as usize] = config;
+            FakeBus { registers, pointer: 0, writes: Vec::new() }
+        }
+    }
+
+    impl ErrorType for FakeBus {
+        type Error = Infallible;
+    }
+
+    impl I2c for FakeBus {
+        fn transaction(&mut self, address: u8, operations: &mut [Operation<'_>]) -> Result<(), Infallible> {
+            assert_eq!(address, ADDRESS);
+            for operation in operations {
+                match operation {
+                    Operation::Write(bytes) => {
+                        self.pointer = bytes[0] as usize;
+                        for &value in &bytes[1..] {
+                            self.writes.push((self.pointer as u8, value));
+                            self.registers[self.pointer] = value;
+                            self.pointer += 1;
+                        }
+                    }
+                    Operation::Read(buffer) => {
+                        for byte in buffer.iter_mut() {
+                            *byte = self.registers[self.pointer];
+                            self.pointer += 1;
+                        }
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
+
+    // osrs_t x2, osrs_p x16 and the given mode, as the BME280 service might have left it
+    const OWNER_CTRL_MEAS: u8 = 0b010 << 5 | 0b101 << 2;
+    // 1000 ms standby and a x16 filter
+    const OWNER_CONFIG: u8 = 0b101 << 5 | 0b100 << 2;
+
+    #[test]
+    fn attached_sensor_in_normal_mode_is_only_read() {
+        let mut bme280 = BME280::new(FakeBus::new(0b011, OWNER_CTRL_MEAS | MODE_NORMAL, OWNER_CONFIG), ADDRESS);
+
+        bme280.attach().unwrap();
+        bme280.measure_shared().unwrap();
+
+        assert!(bme280.i2c.writes.is_empty());
+    }
+
+    #[test]
+    fn attached_sensor_in_sleep_gets_a_forced_measurement_with_its_own_oversampling() {
+        let mut bme280 = BME280::new(FakeBus::new(0b011, OWNER_CTRL_MEAS | MODE_SLEEP, OWNER_CONFIG), ADDRESS);
+
+        bme280.attach().unwrap();
+        bme280.measure_shared().unwrap();
+
+        // Only the mode bits change, ctrl_hum and config are never written
+        assert_eq!(bme280.i2c.writes, [(REG_CTRL_MEAS, OWNER_CTRL_MEAS | MODE_FORCED)]);
+    }
+
+    #[test]
+    fn unconfigured_sensor_measures_temperature_and_pressure() {
+        let mut bme280 = BME280::new(FakeBus::new(0, 0, 0), ADDRESS);
+
+        bme280.attach().unwrap();
+        bme280.measure_shared().unwrap();
+
+        assert_eq!(bme280.i2c.writes, [(REG_CTRL_MEAS, 0b001 << 5 | 0b001 << 2 | MODE_FORCED)]);
+    }
+
+    // Calibration and readings from the worked example in section 8.1 of the BMP280 datasheet,
+    // which the BME280 shares for temperature and pressure
+    fn datasheet_calibration() -> ([u8; 26], [u8; 7]) {
+        let words: [i32; 12] = [27504, 26435, -1000, 36477, -10685, 3024, 2855, 140, -7, 15500, -14600, 6000];
+        let mut tp = [0; 26];
+        for (i, word) in words.into_iter().enumerate() {
+            tp[i * 2..i * 2 + 2].copy_from_slice(&(word as u16).to_le_bytes());
+        }
+        tp[25] = 75; // dig_H1
+        // dig_H2 362, dig_H3 0, dig_H4 324, dig_H5 50, dig_H6 30
+        (tp, [0x6A, 0x01, 0x00, 0x14, 0x24, 0x03, 30])
+    }
+
+    #[test]
+    fn compensate_matches_the_datasheet_example() {
+        let (tp, h) = datasheet_calibration();
+        let measurement = Calibration::parse(&tp, &h).compensate(519888, 415148, 0);
+
+        // 25.08 °C and 100653.27 Pa with the double precision formulas
+        assert!((measurement.temperature - 25.08).abs() < 0.005, "{}", measurement.temperature);
+        assert!((measurement.pressure - 100653.27).abs() < 0.05, "{}", measurement.pressure);
+    }
+
+    #[test]
+    fn humidity_calibration_shares_nibbles_and_is_clamped() {
+        let (tp, h) = datasheet_calibration();
+        let calibration = Calibration::parse(&tp, &h);
+        assert_eq!((calibration.h1, calibration.h2, calibration.h3), (75.0, 362.0, 0.0));
+        assert_eq!((calibration.h4, calibration.h5, calibration.h6), (324.0, 50.0, 30.0));
+
+        // dig_H4 and dig_H5 are signed, the sign comes from their whole byte
+        let negative = Calibration::parse(&tp, &[0x6A, 0x01, 0x00, 0xFF, 0xFF, 0xFF, 0xE2]);
+        assert_eq!((negative.h4, negative.h5, negative.h6), (-1.0, -1.0, -30.0));
+
+        assert_eq!(calibration.compensate(519888, 415148, 0).humidity, 0.0);
+        assert_eq!(calibration.compensate(519888, 415148, 0xFFFF).humidity, 100.0);
+        let humidity = calibration.compensate(519888, 415148, 30000).humidity;
+        assert!(humidity > 0.0 && humidity < 100.0, "{}", humidity);
+    }
+
+    #[test]
+    fn settings_serialize_as_counts_and_milliseconds() {
+        for samples in [0, 1, 2, 4, 8, 16] {
+            let oversampling: Oversampling = serde_json::from_value(samples.into()).unwrap();
+            assert_eq!(serde_json::to_value(oversampling).unwrap(), samples);
+        }
+        for coefficient in [0, 2, 4, 8, 16] {
+            let filter: Filter = serde_json::from_value(coefficient.into()).unwrap();
+            assert_eq!(serde_json::to_value(filter).unwrap(), coefficient);
+        }
+        for ms in [0.5, 10.0, 20.0, 62.5, 125.0, 250.0, 500.0, 1000.0] {
+            let standby: Standby = serde_json::from_value(ms.into()).unwrap();
+            assert_eq!(serde_json::to_value(standby).unwrap(), ms);
+        }
+
+        let settings = Settings::default();
+        let json = serde_json::to_value(settings).unwrap();
+        assert_eq!(serde_json::from_value::<Settings>(json).unwrap(), settings);
+    }
+
+    #[test]
+    fn settings_reject_values_the_chip_cannot_use() {
+        assert!(serde_json::from_value::<Oversampling>(3.into()).is_err());
+        assert!(serde_json::from_value::<Filter>(1.into()).is_err());
+        assert!(serde_json::from_value::<Standby>(100.0.into()).is_err());
+    }
+
+    #[test]
+    fn begin_gives_up_on_a_stuck_nvm_copy() {
+        let mut bus = FakeBus::new(0, 0, 0);
+        bus.registers[REG_STATUS as usize] = STATUS_IM_UPDATE;
+        let mut bme280 = BME280::new(bus, ADDRESS);
+
+        assert!(matches!(bme280.begin(Settings::default()), Err(Error::NotReady)));
+    }
+
+    #[test]
+    fn attach_checks_the_chip_id() {
+        let mut bus = FakeBus::new(0, 0, 0);
+        bus.registers[REG_CHIP_ID as usize] = 0x58; // BMP280
+        let mut bme280 = BME280::new(bus, ADDRESS);
+
+        assert!(matches!(bme280.attach(), Err(Error::WrongDevice)));
+    }
+}
@@ -0,0 +1,16 @@
+// The BME280 driver, also used by the SCD-41 service to read ambient pressure from a shared chip
+
+pub mod bme280;
+
+use sensor_core::SensorError;
+
+// Sort driver errors into the shared error codes
+impl<E: embedded_hal::i2c::Error> From<bme280::Error<E>> for SensorError {
+    fn from(e: bme280::Error<E>) -> Self {
+        match e {
+            bme280::Error::I2c(e) => SensorError::i2c(e),
+            bme280::Error::WrongDevice => SensorError::NotFound(String::from("Chip ID is not a BME280")),
+            bme280::Error::NotReady => SensorError::Timeout(String::from("Measurement did not finish in time")),
+        }
+    }
+}
@@ -1,127 +1,216 @@
-use actix_web::{web, App, HttpServer, HttpResponse, Responder, middleware::Logger}; // Import necessary Actix Web components
-use bme280::i2c::BME280; // Import BME280 sensor library
-use linux_embedded_hal::{I2cdev, Delay};  // Import I2C device and delay from linux_embedded_hal
+mod barometric;
+
+use actix_web::{http::StatusCode, web, HttpResponse}; // Import web framework for /reference_pressure
+use bme280_api::bme280::{Settings, BME280}; // Import BME280 driver
+use linux_embedded_hal::I2cdev;  // Import I2C device from linux_embedded_hal
 use serde::{Deserialize, Serialize}; // Import serialization/deserialization from Serde
-use chrono::Utc; // Import Utc for timestamps
-use std::fs; // Import filesystem operations
-use std::fs::File; // Import file operations
-use std::io::Write; // Import write operations
-use env_logger::Env; // Import environment logger
+use std::sync::RwLock;
+use sensor_core::{error_response, Admin, Psychrometrics, Sensor, SensorConfig, SensorError, ServerConfig}; // Import shared sensor server
+
+// Sea level pressures accepted from /reference_pressure, well beyond any recorded
+const REFERENCE_PRESSURE_RANGE: std::ops::RangeInclusive<f32> = 850.0..=1100.0;
+
+// Sea level pressure set through /reference_pressure, replaces the one in config.json until restart.
+// Kept outside the sensor so it survives the sampler re-initialising it
+static REFERENCE_PRESSURE: RwLock<Option<f32>> = RwLock::new(None);
+
+// What to report alongside the station pressure
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum PressureMode {
+    Altitude, // Altitude from sea_level_pressure, for a sensor that moves
+    SeaLevel, // QNH and QFF from station_altitude, for a weather station
+}
 
 // Configuration structure for the application
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 struct Config {
-    network_port: u16, // Port for the web server
+    #[serde(flatten)]
+    server: ServerConfig, // Port, bind address and I2C bus path
     sea_level_pressure: f32, // Sea level pressure for altitude calculations
+    #[serde(default = "default_pressure_mode")]
+    pressure_mode: PressureMode, // altitude or sea_level
+    #[serde(default)]
+    station_altitude: f32, // Metres above sea level, used in sea_level mode
     i2c_address_decimal: u16, // I2C address of the BME280 sensor
-    i2c_bus_device_path: String, // Path to the I2C bus device
-    bind_address: String, // Address to bind the web server to
+    #[serde(flatten)]
+    settings: Settings, // Mode, oversampling, IIR filter and standby time
+    #[serde(default)]
+    include_psychrometrics: bool, // Add dew point, absolute humidity, VPD, heat index, humidex and wet bulb
+}
+
+fn default_pressure_mode() -> PressureMode {
+    PressureMode::Altitude
 }
 
 // Default implementation for the Config struct
 impl Default for Config {
     fn default() -> Self {
         Config {
-            network_port: 5000, // Default network port
+            server: ServerConfig::with_port(5000), // Default network port
             sea_level_pressure: 1013.25, // Default sea level pressure
+            pressure_mode: default_pressure_mode(),
+            station_altitude: 0.0,
             i2c_address_decimal: 0x77, // Default I2C address (119 in decimal)
-            i2c_bus_device_path: String::from("/dev/i2c-1"), // Default I2C bus device path
-            bind_address: String::from("0.0.0.0"), // Default bind address
+            settings: Settings::default(), // Forced mode, 1x oversampling, no filter
+            include_psychrometrics: false,
         }
     }
 }
 
-// Function to read the configuration from a file or create a default one if it doesn't exist
-fn read_or_create_config() -> Config {
-    let config_path = "config.json";
-    if let Ok(config_data) = fs::read_to_string(config_path) {
-        if let Ok(config) = serde_json::from_str(&config_data) {
-            return config;
-        }
+impl SensorConfig for Config {
+    fn server(&self) -> &ServerConfig {
+        &self.server
     }
 
-    let default_config = Config::default();
-    let config_data = serde_json::to_string_pretty(&default_config).unwrap();
-    let mut file = File::create(config_path).unwrap();
-    file.write_all(config_data.as_bytes()).unwrap();
-    default_config
+    fn i2c_address(&self) -> Option<u16> {
+        Some(self.i2c_address_decimal)
+    }
 }
 
 // Structure to hold sensor data
 #[derive(Serialize)]
 struct SensorData {
-    timestamp: String,
-    model: String,
     temperature: f32,
     humidity: f32,
     pressure: f32,
-    altitude: f32,
+    altitude: f32, // Station altitude in sea_level mode
+    #[serde(skip_serializing_if = "Option::is_none")]
+    qnh: Option<f32>, // Sea level pressure through the standard atmosphere, sea_level mode only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    qff: Option<f32>, // Sea level pressure using the measured temperature, sea_level mode only
+    #[serde(flatten)]
+    psychrometrics: Option<Psychrometrics>, // Only with include_psychrometrics
 }
 
-async fn get_sensor_data() -> impl Responder {
-    let config = read_or_create_config();
+// Device details in /sensor_info
+#[derive(Serialize)]
+struct DeviceInfo {
+    settings: Settings, // As read back from the sensor
+}
 
-    // Set up the I2C bus and BME280 sensor
-    let i2c_bus = match I2cdev::new(&config.i2c_bus_device_path) {
-        Ok(bus) => bus,
-        Err(_) => return HttpResponse::InternalServerError().body("Failed to open I2C bus"),
-    };
+// Body of /reference_pressure
+#[derive(Serialize, Deserialize)]
+struct ReferencePressure {
+    sea_level_pressure: f32, // hPa, e.g. the QNH from a nearby airport's METAR
+}
+
+struct Bme280Sensor {
+    bme280: BME280<I2cdev>,
+    sea_level_pressure: f32,
+    pressure_mode: PressureMode,
+    station_altitude: f32,
+    include_psychrometrics: bool,
+}
 
-    // Create the delay object from linux_embedded_hal
-    let mut delay = Delay {};
+// Answers from config.json and what was set at runtime, so it works while the sensor is down
+async fn get_reference_pressure(config: web::Data<Config>) -> HttpResponse {
+    let sea_level_pressure = reference_pressure(config.sea_level_pressure);
+    HttpResponse::Ok().json(ReferencePressure { sea_level_pressure })
+}
 
-    // Create BME280 sensor object with the correct I2C address
-    let mut bme280 = BME280::new(i2c_bus, config.i2c_address_decimal as u8);
+// Takes effect from the next reading, config.json is left untouched
+async fn set_reference_pressure(
+    _admin: Admin,
+    config: web::Data<Config>,
+    body: web::Json<ReferencePressure>,
+) -> HttpResponse {
+    if config.pressure_mode != PressureMode::Altitude {
+        let message = "sea_level_pressure is only used in altitude mode, pressure_mode is sea_level";
+        return error_response(StatusCode::CONFLICT, "conflict", message);
+    }
 
-    // Initialize the BME280 sensor with the delay
-    if let Err(e) = bme280.init(&mut delay) {
-        eprintln!("Failed to initialize BME280 sensor: {:?}", e);
-        return HttpResponse::InternalServerError().body("Failed to initialize BME280 sensor");
+    let sea_level_pressure = body.sea_level_pressure;
+    if !REFERENCE_PRESSURE_RANGE.contains(&sea_level_pressure) {
+        let message = format!(
+            "sea_level_pressure must be between {} and {} hPa",
+            REFERENCE_PRESSURE_RANGE.start(),
+            REFERENCE_PRESSURE_RANGE.end()
+        );
+        return error_response(StatusCode::BAD_REQUEST, "bad_request", &message);
     }
 
-    // Read sensor data with the delay
-    let data = match bme280.measure(&mut delay) {
-        Ok(data) => data,
-        Err(e) => {
-            eprintln!("Failed to read sensor data: {:?}", e);
-            return HttpResponse::InternalServerError().body("Failed to read sensor data");
-        }
-    };
-
-    // Convert raw data to values
-    let temperature = data.temperature;
-    let humidity = data.humidity;
-    let pressure = data.pressure / 100.0; // Convert pressure from Pa to hPa
-
-    // Calculate altitude using the formula:
-    // altitude = 44330 * (1.0 - (pressure / config.sea_level_pressure).powf(1.0 / 5.255))
-    let altitude = 44330.0 * (1.0 - (pressure / config.sea_level_pressure).powf(1.0 / 5.255));
-
-    // Create sensor data response
-    let sensor_data = SensorData {
-        timestamp: Utc::now().to_rfc3339(),
-        model: String::from("BME280"),
-        temperature,
-        humidity,
-        pressure,
-        altitude,
-    };
-
-    HttpResponse::Ok().json(sensor_data)
+    *REFERENCE_PRESSURE.write().unwrap_or_else(|e| e.into_inner()) = Some(sea_level_pressure);
+    HttpResponse::Ok().json(ReferencePressure { sea_level_pressure })
 }
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    env_logger::init_from_env(Env::default().default_filter_or("info"));
-
-    let config = read_or_create_config();
-
-    HttpServer::new(|| {
-        App::new()
-            .wrap(Logger::default())
-            .route("/sensor_data", web::get().to(get_sensor_data))
-    })
-    .bind((config.bind_address.as_str(), config.network_port))? // Use bind_address from config
-    .run()
-    .await
-}
\ No newline at end of file
+// sea_level_pressure from config.json unless one has been set at runtime
+fn reference_pressure(configured: f32) -> f32 {
+    REFERENCE_PRESSURE
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .unwrap_or(configured)
+}
+
+impl Sensor for Bme280Sensor {
+    type Config = Config;
+    type Reading = SensorData;
+
+    const MODEL: &'static str = "BME280";
+
+    fn init(config: &Config) -> Result<Self, SensorError> {
+        // Set up the I2C bus and BME280 sensor
+        let i2c_bus = I2cdev::new(&config.server.i2c_bus_device_path).map_err(SensorError::bus)?;
+
+        // Create BME280 sensor object with the correct I2C address
+        let mut bme280 = BME280::new(i2c_bus, config.i2c_address_decimal as u8);
+
+        // Load calibration and apply the configured settings once, they hold until the next init
+        bme280.begin(config.settings)?;
+
+        Ok(Bme280Sensor {
+            bme280,
+            sea_level_pressure: config.sea_level_pressure,
+            pressure_mode: config.pressure_mode,
+            station_altitude: config.station_altitude,
+            include_psychrometrics: config.include_psychrometrics,
+        })
+    }
+
+    fn read(&mut self) -> Result<SensorData, SensorError> {
+        // Triggers a measurement in forced mode, normal mode returns the latest one
+        let data = self.bme280.measure()?;
+
+        // Convert raw data to values
+        let temperature = data.temperature;
+        let humidity = data.humidity;
+        let pressure = data.pressure / 100.0; // Convert pressure from Pa to hPa
+
+        let (altitude, qnh, qff) = match self.pressure_mode {
+            PressureMode::Altitude => (barometric::altitude(pressure, reference_pressure(self.sea_level_pressure)), None, None),
+            PressureMode::SeaLevel => (
+                self.station_altitude,
+                Some(barometric::qnh(pressure, self.station_altitude)),
+                Some(barometric::qff(pressure, self.station_altitude, temperature)),
+            ),
+        };
+
+        Ok(SensorData {
+            temperature,
+            humidity,
+            pressure,
+            altitude,
+            qnh,
+            qff,
+            psychrometrics: self
+                .include_psychrometrics
+                .then(|| Psychrometrics::new(temperature, humidity)),
+        })
+    }
+
+    fn device_info(&mut self) -> Result<serde_json::Value, SensorError> {
+        let info = DeviceInfo {
+            settings: self.bme280.settings()?,
+        };
+        serde_json::to_value(info).map_err(SensorError::read)
+    }
+
+    fn routes(cfg: &mut web::ServiceConfig) {
+        cfg.route("/reference_pressure", web::get().to(get_reference_pressure))
+            .route("/reference_pressure", web::put().to(set_reference_pressure));
+    }
+}
+
+fn main() -> std::io::Result<()> {
+    sensor_core::run::<Bme280Sensor>()
+}
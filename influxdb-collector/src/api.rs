@@ -1,8 +1,48 @@
-use reqwest::blocking::Client;
+use reqwest::Client;
+use serde::Deserialize;
 use serde_json::Value;
+use std::time::Duration;
 
-pub fn fetch_sensor_data(api_url: &str) -> Value {
-    let client = Client::new();
-    let response = client.get(api_url).send().expect("Failed to fetch sensor data");
-    response.json().expect("Failed to parse sensor data")
-}
\ No newline at end of file
+use crate::error::CollectorError;
+
+// Error body the sensor APIs return, { "error": { "code", "message", "sensor", "timestamp" } }
+#[derive(Deserialize, Debug)]
+pub struct SensorApiError {
+    pub code: String,
+    pub message: String,
+    pub sensor: String,
+    pub timestamp: String,
+}
+
+#[derive(Deserialize)]
+struct ErrorBody {
+    error: SensorApiError,
+}
+
+pub async fn fetch_sensor_data(client: &Client, api_url: &str, timeout: Duration) -> Result<Value, CollectorError> {
+    let response = client
+        .get(api_url)
+        .timeout(timeout)
+        .send()
+        .await
+        .map_err(|e| CollectorError::Fetch { url: api_url.to_string(), message: e.to_string() })?;
+
+    let status = response.status();
+    let body = response
+        .text()
+        .await
+        .map_err(|e| CollectorError::Fetch { url: api_url.to_string(), message: e.to_string() })?;
+
+    if !status.is_success() {
+        // Older sensor APIs answer errors with plain text
+        let message = match serde_json::from_str::<ErrorBody>(&body) {
+            Ok(ErrorBody { error }) => {
+                format!("{} reported {} at {}: {}", error.sensor, error.code, error.timestamp, error.message)
+            }
+            Err(_) => body,
+        };
+        return Err(CollectorError::Status { url: api_url.to_string(), status, message });
+    }
+
+    serde_json::from_str(&body).map_err(|e| CollectorError::Decode { url: api_url.to_string(), message: e.to_string() })
+}
@@ -1,32 +1,203 @@
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::BTreeMap;
 use std::fs;
 use std::path::Path;
+use std::time::Duration;
+
+use crate::error::CollectorError;
+use crate::line_protocol::Precision;
+
+pub const CONFIG_PATH: &str = "config.json";
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Config {
-    pub api_urls: Vec<String>, // Changed from a single URL to a list of URLs
+    #[serde(alias = "api_urls", deserialize_with = "deserialize_endpoints")]
+    pub endpoints: Vec<Endpoint>, // Sensor APIs to poll, each a URL or an object with its own settings
+    #[serde(default)]
+    pub output: Output, // What influxdb_url points at, which decides the write path and auth
     pub influxdb_url: String,
-    pub influxdb_api_key: String,
-    pub influxdb_org: String,
-    pub influxdb_bucket: String,
-    pub query_interval: u64, // Interval in seconds
+    #[serde(default)]
+    pub influxdb_api_key: String, // Token for InfluxDB 2 and 3, or bearer token for the others
+    #[serde(default)]
+    pub influxdb_org: String, // InfluxDB 2 only
+    #[serde(default)]
+    pub influxdb_bucket: String, // InfluxDB 2 only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub influxdb_database: Option<String>, // InfluxDB 1.x and 3
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub influxdb_retention_policy: Option<String>, // InfluxDB 1.x, the database's default if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub influxdb_username: Option<String>, // Basic auth, for everything but InfluxDB 2 and 3
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub influxdb_password: Option<String>,
+    #[serde(default)]
+    pub influxdb_gzip: bool, // Compress write requests
+    #[serde(default)]
+    pub influxdb_precision: Precision, // Timestamp precision of written points, s, ms, us or ns
+    pub query_interval: u64, // Interval in seconds between writes, and between polls unless an endpoint sets its own
+    #[serde(default = "default_request_timeout_s")]
+    pub request_timeout_s: u64, // How long a sensor API gets to answer
+    #[serde(default = "default_write_timeout_s")]
+    pub write_timeout_s: u64, // How long InfluxDB gets to accept a batch
+    #[serde(default = "default_spool_dir")]
+    pub spool_dir: String, // Batches InfluxDB couldn't take wait here until it can
+    #[serde(default = "default_spool_max_bytes")]
+    pub spool_max_bytes: u64, // The oldest batches are dropped beyond this
+    #[serde(default = "default_spool_max_age_s")]
+    pub spool_max_age_s: u64, // Batches older than this are dropped
+}
+
+// Where batches are written, each takes line protocol on its own path
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Output {
+    #[default]
+    InfluxDb2,
+    InfluxDb1,
+    InfluxDb3,
+    VictoriaMetrics,
+    QuestDb,
+}
+
+impl Output {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Output::InfluxDb2 => "influxdb2",
+            Output::InfluxDb1 => "influxdb1",
+            Output::InfluxDb3 => "influxdb3",
+            Output::VictoriaMetrics => "victoriametrics",
+            Output::QuestDb => "questdb",
+        }
+    }
+}
+
+// A sensor API to poll. The settings are all optional, so the old list of plain URLs still works.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Endpoint {
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interval_s: Option<u64>, // Defaults to query_interval
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_s: Option<u64>, // Defaults to request_timeout_s
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub measurement: Option<String>, // Defaults to the sensor's model
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tags: BTreeMap<String, String>, // Added to every point, such as room, floor or device id
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>, // Only these fields are written, or every field if empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude: Vec<String>, // Fields that are never written
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub rename: BTreeMap<String, String>, // Field or tag names as the sensor sends them, to the names written
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub scale: BTreeMap<String, Scale>, // Unit conversions, by field name as the sensor sends it
+}
+
+// Written value = sensor value * factor + offset, e.g. hPa to kPa is { "factor": 0.1 }
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct Scale {
+    #[serde(default = "default_factor")]
+    pub factor: f64,
+    #[serde(default)]
+    pub offset: f64,
+}
+
+fn default_factor() -> f64 {
+    1.0
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum EndpointEntry {
+    Url(String),
+    Endpoint(Box<Endpoint>),
+}
+
+fn deserialize_endpoints<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Endpoint>, D::Error> {
+    let entries = Vec::<EndpointEntry>::deserialize(deserializer)?;
+    Ok(entries
+        .into_iter()
+        .map(|entry| match entry {
+            EndpointEntry::Url(url) => Endpoint { url, ..Endpoint::default() },
+            EndpointEntry::Endpoint(endpoint) => *endpoint,
+        })
+        .collect())
+}
+
+impl Config {
+    pub fn interval(&self, endpoint: &Endpoint) -> Duration {
+        Duration::from_secs(endpoint.interval_s.unwrap_or(self.query_interval))
+    }
+
+    pub fn timeout(&self, endpoint: &Endpoint) -> Duration {
+        Duration::from_secs(endpoint.timeout_s.unwrap_or(self.request_timeout_s))
+    }
+}
+
+fn default_request_timeout_s() -> u64 {
+    10
 }
 
-pub fn create_config() -> Config {
-    let config_path = "config.json";
-    if !Path::new(config_path).exists() {
+fn default_write_timeout_s() -> u64 {
+    30
+}
+
+fn default_spool_dir() -> String {
+    "spool".to_string()
+}
+
+fn default_spool_max_bytes() -> u64 {
+    50 * 1024 * 1024
+}
+
+fn default_spool_max_age_s() -> u64 {
+    7 * 24 * 60 * 60
+}
+
+// Read config.json, writing one with placeholder values first if there isn't one
+pub fn create_config() -> Result<Config, CollectorError> {
+    let config_error = |message: String| CollectorError::Config { path: CONFIG_PATH.to_string(), message };
+
+    if !Path::new(CONFIG_PATH).exists() {
         let default_config = Config {
-            api_urls: vec!["http://localhost:5000/sensor_data".to_string()], // Default list of URLs
+            endpoints: vec![Endpoint {
+                url: "http://localhost:5000/sensor_data".to_string(),
+                ..Endpoint::default()
+            }],
+            output: Output::default(),
             influxdb_url: "http://localhost:8086".to_string(),
             influxdb_api_key: "your_api_key".to_string(),
             influxdb_org: "your_org".to_string(),
             influxdb_bucket: "your_bucket".to_string(),
+            influxdb_database: None,
+            influxdb_retention_policy: None,
+            influxdb_username: None,
+            influxdb_password: None,
+            influxdb_gzip: false,
+            influxdb_precision: Precision::default(),
             query_interval: 60, // Default interval of 60 seconds
+            request_timeout_s: default_request_timeout_s(),
+            write_timeout_s: default_write_timeout_s(),
+            spool_dir: default_spool_dir(),
+            spool_max_bytes: default_spool_max_bytes(),
+            spool_max_age_s: default_spool_max_age_s(),
         };
-        let config_data = serde_json::to_string_pretty(&default_config).unwrap();
-        fs::write(config_path, config_data).expect("Unable to write config file");
+        let config_data = serde_json::to_string_pretty(&default_config)
+            .map_err(|e| config_error(format!("Unable to serialise default config: {}", e)))?;
+        fs::write(CONFIG_PATH, config_data).map_err(|e| config_error(format!("Unable to write: {}", e)))?;
     }
 
-    let config_data = fs::read_to_string(config_path).expect("Unable to read config file");
-    serde_json::from_str(&config_data).expect("Unable to parse config file")
-}
\ No newline at end of file
+    let config_data = fs::read_to_string(CONFIG_PATH).map_err(|e| config_error(format!("Unable to read: {}", e)))?;
+    let config: Config =
+        serde_json::from_str(&config_data).map_err(|e| config_error(format!("Unable to parse: {}", e)))?;
+
+    // A zero interval would make tokio's interval panic
+    if config.query_interval == 0 {
+        return Err(config_error(String::from("query_interval must be at least 1 second")));
+    }
+    if let Some(endpoint) = config.endpoints.iter().find(|endpoint| endpoint.interval_s == Some(0)) {
+        return Err(config_error(format!("interval_s for {} must be at least 1 second", endpoint.url)));
+    }
+
+    Ok(config)
+}
@@ -0,0 +1,84 @@
+use reqwest::StatusCode;
+use std::collections::BTreeMap;
+use std::fmt;
+
+// Everything that can go wrong between a sensor API and InfluxDB. Each error names the
+// endpoint it came from, a sensor API URL, the InfluxDB URL, the spool or the config file.
+#[derive(Debug)]
+pub enum CollectorError {
+    Fetch { url: String, message: String },                       // Couldn't reach the endpoint, or it took too long
+    Status { url: String, status: StatusCode, message: String }, // The endpoint answered with an error status
+    Decode { url: String, message: String },                      // The response wasn't a reading we can use
+    Write { url: String, message: String },                       // Couldn't reach InfluxDB
+    Spool { path: String, message: String },                      // Couldn't keep or replay a batch on disk
+    Config { path: String, message: String },                     // Missing, unreadable or invalid config
+}
+
+impl CollectorError {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            CollectorError::Fetch { .. } => "fetch",
+            CollectorError::Status { .. } => "status",
+            CollectorError::Decode { .. } => "decode",
+            CollectorError::Write { .. } => "write",
+            CollectorError::Spool { .. } => "spool",
+            CollectorError::Config { .. } => "config",
+        }
+    }
+
+    pub fn endpoint(&self) -> &str {
+        match self {
+            CollectorError::Fetch { url, .. }
+            | CollectorError::Status { url, .. }
+            | CollectorError::Decode { url, .. }
+            | CollectorError::Write { url, .. } => url,
+            CollectorError::Spool { path, .. } | CollectorError::Config { path, .. } => path,
+        }
+    }
+
+    // Whether sending the same request again could succeed. Malformed points or a batch over
+    // InfluxDB's size limit are refused every time, and would block the spool forever.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            CollectorError::Fetch { .. } | CollectorError::Write { .. } => true,
+            CollectorError::Status { status, .. } => !matches!(
+                *status,
+                StatusCode::BAD_REQUEST | StatusCode::PAYLOAD_TOO_LARGE | StatusCode::UNPROCESSABLE_ENTITY
+            ),
+            CollectorError::Decode { .. } | CollectorError::Spool { .. } | CollectorError::Config { .. } => false,
+        }
+    }
+}
+
+impl fmt::Display for CollectorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CollectorError::Fetch { url, message } => write!(f, "Failed to fetch {}: {}", url, message),
+            CollectorError::Status { url, status, message } => write!(f, "{} returned {}: {}", url, status, message),
+            CollectorError::Decode { url, message } => write!(f, "Unusable response from {}: {}", url, message),
+            CollectorError::Write { url, message } => write!(f, "Failed to write to {}: {}", url, message),
+            CollectorError::Spool { path, message } => write!(f, "Spool {}: {}", path, message),
+            CollectorError::Config { path, message } => write!(f, "Config file {}: {}", path, message),
+        }
+    }
+}
+
+impl std::error::Error for CollectorError {}
+
+// Errors seen so far, per endpoint and kind, so a log line shows whether a problem is new
+#[derive(Default)]
+pub struct ErrorCounts {
+    counts: BTreeMap<(String, &'static str), u64>,
+}
+
+impl ErrorCounts {
+    // Count the error and log it with what the collector does about it
+    pub fn report(&mut self, error: &CollectorError, action: &str) {
+        let count = self
+            .counts
+            .entry((error.endpoint().to_string(), error.kind()))
+            .or_insert(0);
+        *count += 1;
+        eprintln!("{}, {} ({} {} errors from this endpoint)", error, action, count, error.kind());
+    }
+}
@@ -1,40 +1,149 @@
-use reqwest::blocking::Client;
-use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
-use serde_json::Value;
-use crate::utils::get_hostname;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use reqwest::header::{HeaderValue, AUTHORIZATION, CONTENT_ENCODING};
+use reqwest::{Client, Url};
+use std::io::Write;
+use std::time::Duration;
 
-pub fn send_log(influxdb_url: &str, api_key: &str, org: &str, bucket: &str, sensor_data: &Value) {
-    let client = Client::new();
-    let mut headers = HeaderMap::new();
-    headers.insert(AUTHORIZATION, HeaderValue::from_str(&format!("Token {}", api_key)).unwrap());
+use crate::config::{Config, Output, CONFIG_PATH};
+use crate::error::CollectorError;
+use crate::line_protocol::Precision;
 
-    let write_url = format!("{}/api/v2/write?org={}&bucket={}&precision=s", influxdb_url, org, bucket);
-
-    // Convert sensor data to line protocol format
-    let line_protocol = convert_to_line_protocol(sensor_data);
+enum Auth {
+    None,
+    Header(HeaderValue), // Token or Bearer
+    Basic { username: String, password: Option<String> },
+}
 
-    let response = client.post(&write_url)
-        .headers(headers)
-        .body(line_protocol)
-        .send()
-        .expect("Failed to send log to InfluxDB");
+// Writes batches of line protocol to InfluxDB, or anything else that takes it over HTTP
+pub struct InfluxDb {
+    client: Client,
+    write_url: Url,
+    auth: Auth,
+    gzip: bool,
+    timeout: Duration,
+}
 
-    if !response.status().is_success() {
-        eprintln!("Failed to send log to InfluxDB: {}", response.status());
+// The precision query value each output understands
+fn precision(output: Output, precision: Precision) -> &'static str {
+    match (output, precision) {
+        (Output::InfluxDb1 | Output::QuestDb, Precision::Ns) => "n",
+        (Output::InfluxDb1 | Output::QuestDb, Precision::Us) => "u",
+        (Output::InfluxDb3, Precision::Ns) => "nanosecond",
+        (Output::InfluxDb3, Precision::Us) => "microsecond",
+        (Output::InfluxDb3, Precision::Ms) => "millisecond",
+        (Output::InfluxDb3, Precision::S) => "second",
+        _ => precision.as_str(),
     }
 }
 
-fn convert_to_line_protocol(sensor_data: &Value) -> String {
-    let hostname = get_hostname();
-    let measurement = sensor_data.get("model").and_then(Value::as_str).unwrap_or("unknown");
+fn config_error(message: String) -> CollectorError {
+    CollectorError::Config { path: String::from(CONFIG_PATH), message }
+}
+
+fn required<'a>(value: &'a Option<String>, name: &str, output: Output) -> Result<&'a str, CollectorError> {
+    value
+        .as_deref()
+        .filter(|value| !value.is_empty())
+        .ok_or_else(|| config_error(format!("{} is required for output {}", name, output.as_str())))
+}
+
+impl InfluxDb {
+    pub fn new(client: Client, config: &Config) -> Result<Self, CollectorError> {
+        let output = config.output;
+        let base = config.influxdb_url.trim_end_matches('/');
+        let path = match output {
+            Output::InfluxDb2 => "/api/v2/write",
+            Output::InfluxDb3 => "/api/v3/write_lp",
+            Output::InfluxDb1 | Output::VictoriaMetrics | Output::QuestDb => "/write",
+        };
+        let mut write_url = Url::parse(&format!("{}{}", base, path))
+            .map_err(|e| config_error(format!("Invalid influxdb_url {}: {}", config.influxdb_url, e)))?;
+
+        {
+            let mut query = write_url.query_pairs_mut();
+            match output {
+                Output::InfluxDb2 => {
+                    if config.influxdb_org.is_empty() || config.influxdb_bucket.is_empty() {
+                        let message = "influxdb_org and influxdb_bucket are required for output influxdb2";
+                        return Err(config_error(String::from(message)));
+                    }
+                    query.append_pair("org", &config.influxdb_org);
+                    query.append_pair("bucket", &config.influxdb_bucket);
+                }
+                Output::InfluxDb1 | Output::InfluxDb3 => {
+                    query.append_pair("db", required(&config.influxdb_database, "influxdb_database", output)?);
+                }
+                Output::VictoriaMetrics | Output::QuestDb => {}
+            }
+            if let (Output::InfluxDb1, Some(retention_policy)) = (output, &config.influxdb_retention_policy) {
+                query.append_pair("rp", retention_policy);
+            }
+            query.append_pair("precision", precision(output, config.influxdb_precision));
+        }
+
+        // InfluxDB 2 and 3 only take tokens, the others use basic auth when there's a username
+        let token = match output {
+            Output::InfluxDb2 => Some(format!("Token {}", config.influxdb_api_key)),
+            _ if config.influxdb_api_key.is_empty() => None,
+            _ => Some(format!("Bearer {}", config.influxdb_api_key)),
+        };
+        let auth = match (&config.influxdb_username, token) {
+            (Some(username), _) if !matches!(output, Output::InfluxDb2 | Output::InfluxDb3) => Auth::Basic {
+                username: username.clone(),
+                password: config.influxdb_password.clone(),
+            },
+            // Checked up front, a key that can't be sent as a header would fail every write
+            (_, Some(token)) => Auth::Header(
+                HeaderValue::from_str(&token).map_err(|e| config_error(format!("Invalid influxdb_api_key: {}", e)))?,
+            ),
+            (_, None) => Auth::None,
+        };
+
+        Ok(InfluxDb {
+            client,
+            write_url,
+            auth,
+            gzip: config.influxdb_gzip,
+            timeout: Duration::from_secs(config.write_timeout_s),
+        })
+    }
 
-    // Assuming sensor_data is a JSON object with key-value pairs
-    let mut lines = Vec::new();
-    if let Some(obj) = sensor_data.as_object() {
-        for (key, value) in obj {
-            let line = format!("{},host={} {}={}", measurement, hostname, key, value);
-            lines.push(line);
+    // Write one or more lines of line protocol in a single request
+    pub async fn write(&self, batch: &str) -> Result<(), CollectorError> {
+        let url = self.write_url.to_string();
+        let mut request = self.client.post(self.write_url.clone()).timeout(self.timeout);
+        request = match &self.auth {
+            Auth::None => request,
+            Auth::Header(value) => request.header(AUTHORIZATION, value.clone()),
+            Auth::Basic { username, password } => request.basic_auth(username, password.as_ref()),
+        };
+
+        if self.gzip {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            let compressed = encoder
+                .write_all(batch.as_bytes())
+                .and_then(|_| encoder.finish())
+                .map_err(|e| CollectorError::Write {
+                    url: url.clone(),
+                    message: format!("Failed to compress batch: {}", e),
+                })?;
+            request = request.header(CONTENT_ENCODING, "gzip").body(compressed);
+        } else {
+            request = request.body(batch.to_string());
         }
+
+        let response = request
+            .send()
+            .await
+            .map_err(|e| CollectorError::Write { url: url.clone(), message: e.to_string() })?;
+
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+
+        let message = response.text().await.unwrap_or_default();
+        Err(CollectorError::Status { url, status, message })
     }
-    lines.join("\n")
-}
\ No newline at end of file
+}
@@ -0,0 +1,257 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+// Timestamp precision for writes, also passed to InfluxDB as ?precision=
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Precision {
+    Ns,
+    Us,
+    Ms,
+    #[default]
+    S,
+}
+
+impl Precision {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Precision::Ns => "ns",
+            Precision::Us => "us",
+            Precision::Ms => "ms",
+            Precision::S => "s",
+        }
+    }
+}
+
+// Measurement names escape commas and spaces, line breaks can't be escaped at all so become spaces
+fn escape_measurement(value: &str) -> String {
+    value
+        .replace(['\n', '\r'], " ")
+        .replace('\\', "\\\\")
+        .replace(',', "\\,")
+        .replace(' ', "\\ ")
+}
+
+// Tag keys, tag values and field keys also escape equals signs
+fn escape_key(value: &str) -> String {
+    value
+        .replace(['\n', '\r'], " ")
+        .replace('\\', "\\\\")
+        .replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ")
+}
+
+fn timestamp(time: DateTime<Utc>, precision: Precision) -> Option<i64> {
+    match precision {
+        Precision::Ns => time.timestamp_nanos_opt(),
+        Precision::Us => Some(time.timestamp_micros()),
+        Precision::Ms => Some(time.timestamp_millis()),
+        Precision::S => Some(time.timestamp()),
+    }
+}
+
+// Encode a /sensor_data reading as a single point, the measurement is usually the model
+//
+//   BME280,host=pi,model=BME280 humidity=40,pressure=1013.25,temperature=25 1696161600
+//
+// Numbers become float fields, so a field keeps its type whether or not the sensor sent a
+// fraction, flags become booleans and other strings become tags. The point takes the reading's
+// own timestamp, or `collected_at` if it doesn't have one, so spooled points keep their time.
+pub fn encode(
+    sensor_data: &Value,
+    measurement: &str,
+    host: &str,
+    precision: Precision,
+    collected_at: DateTime<Utc>,
+) -> Result<String, String> {
+    let reading = sensor_data
+        .as_object()
+        .ok_or_else(|| String::from("Sensor data is not a JSON object"))?;
+
+    let mut tags = BTreeMap::new();
+    let mut fields = BTreeMap::new();
+    tags.insert("host", host);
+
+    for (key, value) in reading {
+        match value {
+            _ if key == "timestamp" => {}
+            Value::String(tag) => {
+                tags.insert(key.as_str(), tag.as_str());
+            }
+            Value::Number(n) => {
+                if let Some(n) = n.as_f64() {
+                    fields.insert(key.as_str(), n.to_string());
+                }
+            }
+            Value::Bool(b) => {
+                fields.insert(key.as_str(), b.to_string());
+            }
+            _ => {} // Nulls and nested values have no line protocol equivalent
+        }
+    }
+
+    if fields.is_empty() {
+        return Err(format!("{} reading has no numeric fields", measurement));
+    }
+
+    let mut line = escape_measurement(measurement);
+    // Empty tag values aren't allowed, tags are sorted as InfluxDB recommends
+    for (key, value) in tags.iter().filter(|(_, value)| !value.is_empty()) {
+        line.push_str(&format!(",{}={}", escape_key(key), escape_key(value)));
+    }
+
+    let fields: Vec<String> = fields
+        .iter()
+        .map(|(key, value)| format!("{}={}", escape_key(key), value))
+        .collect();
+    line.push(' ');
+    line.push_str(&fields.join(","));
+
+    let time = reading
+        .get("timestamp")
+        .and_then(Value::as_str)
+        .and_then(|value| DateTime::parse_from_rfc3339(value).ok())
+        .map_or(collected_at, |time| time.with_timezone(&Utc));
+    if let Some(timestamp) = timestamp(time, precision) {
+        line.push_str(&format!(" {}", timestamp));
+    }
+
+    Ok(line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use serde_json::json;
+    use std::fs;
+    use std::path::Path;
+
+    fn collected_at() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2023, 10, 1, 12, 0, 0).unwrap()
+    }
+
+    fn encode_s(sensor_data: &Value, measurement: &str) -> String {
+        encode(sensor_data, measurement, "pi", Precision::S, collected_at()).unwrap()
+    }
+
+    #[test]
+    fn reading_becomes_one_point() {
+        let reading = json!({
+            "timestamp": "2023-10-01T12:00:00Z",
+            "model": "BME280",
+            "temperature": 25,
+            "humidity": 40.5,
+        });
+        assert_eq!(
+            encode_s(&reading, "BME280"),
+            "BME280,host=pi,model=BME280 humidity=40.5,temperature=25 1696161600"
+        );
+    }
+
+    #[test]
+    fn escapes_each_part() {
+        let reading = json!({
+            "model": "a b",
+            "room name": "kitchen, back=1",
+            "path": "C:\\sensors",
+            "note": "two\nlines",
+            "temp,°C=x": 21.5,
+        });
+        assert_eq!(
+            encode_s(&reading, "climate, indoor\\"),
+            "climate\\,\\ indoor\\\\,host=pi,model=a\\ b,note=two\\ lines,path=C:\\\\sensors,\
+             room\\ name=kitchen\\,\\ back\\=1 temp\\,°C\\=x=21.5 1696161600"
+        );
+    }
+
+    #[test]
+    fn line_breaks_become_escaped_spaces() {
+        assert_eq!(
+            encode_s(&json!({ "lux": 1 }), "living\r\nroom"),
+            "living\\ \\ room,host=pi lux=1 1696161600"
+        );
+    }
+
+    #[test]
+    fn strings_become_tags_and_empty_tags_are_dropped() {
+        let reading = json!({ "model": "SCD-41", "location": "", "serial": "abc", "co2": 612 });
+        assert_eq!(
+            encode_s(&reading, "SCD-41"),
+            "SCD-41,host=pi,model=SCD-41,serial=abc co2=612 1696161600"
+        );
+    }
+
+    #[test]
+    fn bools_are_fields_and_nulls_are_skipped() {
+        let reading = json!({ "saturated": true, "overflow": false, "lux": 12.5, "spare": null, "nested": {} });
+        assert_eq!(
+            encode_s(&reading, "TSL2591"),
+            "TSL2591,host=pi lux=12.5,overflow=false,saturated=true 1696161600"
+        );
+    }
+
+    #[test]
+    fn timestamp_in_each_precision() {
+        let reading = json!({ "timestamp": "2023-10-01T12:00:00.123456789Z", "lux": 1 });
+        let expected = [
+            (Precision::Ns, "1696161600123456789"),
+            (Precision::Us, "1696161600123456"),
+            (Precision::Ms, "1696161600123"),
+            (Precision::S, "1696161600"),
+        ];
+        for (precision, timestamp) in expected {
+            let line = encode(&reading, "BH1750", "pi", precision, collected_at()).unwrap();
+            assert_eq!(line, format!("BH1750,host=pi lux=1 {}", timestamp), "{:?}", precision);
+        }
+    }
+
+    #[test]
+    fn missing_or_malformed_timestamp_uses_collected_at() {
+        let collected = "BH1750,host=pi lux=1 1696161600";
+        assert_eq!(encode_s(&json!({ "lux": 1 }), "BH1750"), collected);
+        assert_eq!(encode_s(&json!({ "lux": 1, "timestamp": "yesterday" }), "BH1750"), collected);
+        assert_eq!(encode_s(&json!({ "lux": 1, "timestamp": 1696161600 }), "BH1750"), collected);
+    }
+
+    #[test]
+    fn offset_timestamps_are_converted_to_utc() {
+        let reading = json!({ "lux": 1, "timestamp": "2023-10-01T14:00:00+02:00" });
+        assert_eq!(encode_s(&reading, "BH1750"), "BH1750,host=pi lux=1 1696161600");
+    }
+
+    #[test]
+    fn reading_without_numeric_fields_is_an_error() {
+        let reading = json!({ "model": "BME280", "timestamp": "2023-10-01T12:00:00Z" });
+        assert_eq!(
+            encode(&reading, "BME280", "pi", Precision::S, collected_at()),
+            Err(String::from("BME280 reading has no numeric fields"))
+        );
+        assert!(encode(&json!([1, 2]), "BME280", "pi", Precision::S, collected_at()).is_err());
+    }
+
+    // Each src/testdata/<name>.json reading must encode to exactly the line in <name>.lp, readings
+    // without a timestamp get 2023-10-01T12:01:00Z
+    #[test]
+    fn golden_files() {
+        let testdata = Path::new(env!("CARGO_MANIFEST_DIR")).join("src/testdata");
+        let mut checked = 0;
+        for entry in fs::read_dir(&testdata).unwrap() {
+            let input = entry.unwrap().path();
+            if input.extension().is_none_or(|extension| extension != "json") {
+                continue;
+            }
+            let reading: Value = serde_json::from_str(&fs::read_to_string(&input).unwrap()).unwrap();
+            let expected = fs::read_to_string(input.with_extension("lp")).unwrap();
+            let measurement = reading["model"].as_str().unwrap();
+            let collected_at = Utc.with_ymd_and_hms(2023, 10, 1, 12, 1, 0).unwrap();
+            let line = encode(&reading, measurement, "pi", Precision::S, collected_at).unwrap();
+            assert_eq!(line, expected.trim_end(), "{}", input.display());
+            checked += 1;
+        }
+        assert!(checked > 0, "no golden files in {}", testdata.display());
+    }
+}
@@ -1,80 +1,477 @@
 mod api;
 mod config;
+mod error;
 mod influxdb;
+mod line_protocol;
+mod mapping;
+mod spool;
 mod utils;
 
-use crate::config::create_config;
+use crate::config::{create_config, Config, Endpoint};
 use crate::api::fetch_sensor_data;
-use crate::influxdb::send_log;
+use crate::error::{CollectorError, ErrorCounts};
+use crate::influxdb::InfluxDb;
+use crate::line_protocol::{encode, Precision};
+use crate::mapping::{map_fields, measurement};
+use crate::spool::Spool;
+use crate::utils::get_hostname;
 //use crate::utils::{log_error, log_info};
-use std::sync::{Arc, Mutex};
-use tokio::time::{interval, Duration};
+use chrono::{DateTime, Utc};
+use reqwest::Client;
+use serde_json::Value;
+use std::process;
+use std::sync::Arc;
+use tokio::sync::mpsc::{self, Sender};
+use tokio::time::{interval, timeout, Duration, MissedTickBehavior};
 use tokio_retry::strategy::{ExponentialBackoff, jitter};
-use tokio_retry::Retry;
+use tokio_retry::RetryIf;
+
+// Readings waiting for the next write, pollers wait for room beyond this
+const READINGS_CAPACITY: usize = 10_000;
+
+// A reading as a poller hands it over, it is encoded when its batch is written
+struct Reading {
+    url: String,
+    measurement: String,
+    sensor_data: Value,
+}
 
 #[tokio::main]
 async fn main() {
     // Load or create the configuration
-    let config = create_config();
-    let config = Arc::new(config);
+    let config = match create_config() {
+        Ok(config) => Arc::new(config),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+    let hostname = get_hostname();
 
-    // Create an interval based on the config setting
+    // One client for every request, so connections are reused
+    let client = Client::new();
+    let influxdb = match InfluxDb::new(client.clone(), &config) {
+        Ok(influxdb) => influxdb,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+    let spool = Spool::new(&config.spool_dir, config.spool_max_bytes, config.spool_max_age_s).await;
+
+    // Batches are written every query_interval, whatever the endpoints' own intervals
     let mut interval = interval(Duration::from_secs(config.query_interval));
+    // A tick that overran pushes the next one back rather than firing a burst to catch up
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    // Pollers hand readings to the loop below, which writes whatever has arrived each tick
+    let (readings, mut received) = mpsc::channel(READINGS_CAPACITY);
+    for endpoint in &config.endpoints {
+        tokio::spawn(poll(client.clone(), Arc::clone(&config), endpoint.clone(), readings.clone()));
+    }
+
+    let mut errors = ErrorCounts::default();
 
     loop {
         interval.tick().await;
 
-        for api_url in &config.api_urls {
-            let api_url = api_url.clone();
-            let config = Arc::clone(&config);
+        // Readings without a timestamp of their own all get the tick's
+        let collected_at = Utc::now();
 
-            // Retry fetching sensor data from the API
-            let sensor_data = match Retry::spawn(ExponentialBackoff::from_millis(10).map(jitter).take(5), {
-                let api_url = api_url.clone();
-                move || {
-                    let api_url = api_url.clone();
-                    tokio::task::spawn_blocking(move || fetch_sensor_data(&api_url))
-                }
-            })
-            .await {
-                Ok(data) => data,
-                Err(_) => {
-                    //log_error(&format!("Failed to fetch sensor data from {} after retries", api_url));
-                    continue; // Skip to the next API URL
+        // Every reading since the last tick goes to InfluxDB in one write
+        let mut readings = Vec::new();
+        while let Ok(reading) = received.try_recv() {
+            readings.push(reading);
+        }
+
+        let lines = encode_batch(&readings, &hostname, config.influxdb_precision, collected_at, &mut errors);
+        if !lines.is_empty() {
+            deliver(&influxdb, &spool, &mut errors, lines.join("\n")).await;
+        }
+    }
+}
+
+// Poll one sensor on its own interval, so a slow or dead one doesn't hold up the others
+async fn poll(client: Client, config: Arc<Config>, endpoint: Endpoint, readings: Sender<Reading>) {
+    let every = config.interval(&endpoint);
+    let mut interval = interval(every);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let mut errors = ErrorCounts::default();
+
+    loop {
+        interval.tick().await;
+
+        let sensor_data = match fetch(&client, &endpoint, config.timeout(&endpoint), every).await {
+            Ok(data) => data,
+            Err(e) => {
+                errors.report(&e, "skipping it this tick"); // The sensor's error code and message when it sent one
+                continue;
+            }
+        };
+
+        let measurement = measurement(&endpoint, &sensor_data);
+        let sensor_data = map_fields(&endpoint, sensor_data);
+        let reading = Reading { url: endpoint.url.clone(), measurement, sensor_data };
+        if readings.send(reading).await.is_err() {
+            return; // The writer has gone
+        }
+    }
+}
+
+// Encode a tick's readings as line protocol, leaving out any that can't be
+fn encode_batch(
+    readings: &[Reading],
+    hostname: &str,
+    precision: Precision,
+    collected_at: DateTime<Utc>,
+    errors: &mut ErrorCounts,
+) -> Vec<String> {
+    readings
+        .iter()
+        .filter_map(|reading| {
+            match encode(&reading.sensor_data, &reading.measurement, hostname, precision, collected_at) {
+                Ok(line) => Some(line),
+                Err(message) => {
+                    let e = CollectorError::Decode { url: reading.url.clone(), message };
+                    errors.report(&e, "skipping the reading");
+                    None
                 }
-            };
-
-            let influxdb_url = config.influxdb_url.clone();
-            let influxdb_api_key = config.influxdb_api_key.clone();
-            let influxdb_org = config.influxdb_org.clone();
-            let influxdb_bucket = config.influxdb_bucket.clone();
-            let sensor_data = Arc::new(Mutex::new(sensor_data));
-
-            // Retry sending the logs to InfluxDB
-            if let Err(_) = Retry::spawn(ExponentialBackoff::from_millis(10).map(jitter).take(5), {
-                let influxdb_url = influxdb_url.clone();
-                let influxdb_api_key = influxdb_api_key.clone();
-                let influxdb_org = influxdb_org.clone();
-                let influxdb_bucket = influxdb_bucket.clone();
-                let sensor_data = Arc::clone(&sensor_data);
-                move || {
-                    let influxdb_url = influxdb_url.clone();
-                    let influxdb_api_key = influxdb_api_key.clone();
-                    let influxdb_org = influxdb_org.clone();
-                    let influxdb_bucket = influxdb_bucket.clone();
-                    let sensor_data = Arc::clone(&sensor_data);
-                    tokio::task::spawn_blocking(move || {
-                        let sensor_data = sensor_data.lock().unwrap();
-                        send_log(&influxdb_url, &influxdb_api_key, &influxdb_org, &influxdb_bucket, &sensor_data)
-                    })
+            }
+        })
+        .collect()
+}
+
+// Fetch one sensor, retrying quickly, but never for longer than its interval
+async fn fetch(
+    client: &Client,
+    endpoint: &Endpoint,
+    request_timeout: Duration,
+    every: Duration,
+) -> Result<Value, CollectorError> {
+    // Retry fetching sensor data from the API, a reading that doesn't parse won't on the next try either
+    let retries = RetryIf::start(
+        ExponentialBackoff::from_millis(2).factor(100).map(jitter).take(3),
+        || fetch_sensor_data(client, &endpoint.url, request_timeout),
+        CollectorError::is_retryable,
+    );
+
+    match timeout(every, retries).await {
+        Ok(result) => result,
+        Err(_) => Err(CollectorError::Fetch {
+            url: endpoint.url.clone(),
+            message: format!("no answer within {} seconds", every.as_secs()),
+        }),
+    }
+}
+
+// Write a batch to InfluxDB, spooling it to disk if that fails. Anything already spooled goes
+// first, so points reach InfluxDB in the order they were collected.
+async fn deliver(influxdb: &InfluxDb, spool: &Spool, errors: &mut ErrorCounts, batch: String) {
+    if spool.is_empty().await {
+        // Retry for a few seconds to ride out blips, then leave it to the spool
+        let result = RetryIf::start(
+            ExponentialBackoff::from_millis(2).factor(100).map(jitter).take(4),
+            || influxdb.write(&batch),
+            CollectorError::is_retryable,
+        )
+        .await;
+
+        match result {
+            Ok(()) => {}
+            Err(e) if !e.is_retryable() => errors.report(&e, "dropping batch"),
+            Err(e) => {
+                // The spool is tried again on the next tick
+                errors.report(&e, "spooling batch");
+                if let Err(e) = spool.push(&batch).await {
+                    errors.report(&e, "batch lost");
                 }
-            })
-            .await {
-                //log_error("Failed to send log to InfluxDB after retries");
-                continue; // Skip to the next API URL
             }
+        }
+        return;
+    }
+
+    if let Err(e) = spool.push(&batch).await {
+        errors.report(&e, "batch lost");
+    }
+
+    let mut sent = 0;
+    for spooled in spool.pending().await {
+        let body = match spool.read(&spooled).await {
+            Ok(body) => body,
+            Err(e) => {
+                errors.report(&e, "stopping replay");
+                break;
+            }
+        };
+
+        match influxdb.write(&body).await {
+            Ok(()) => sent += 1,
+            Err(e) if !e.is_retryable() => errors.report(&e, "dropping spooled batch"),
+            Err(e) => {
+                errors.report(&e, "keeping spooled batches for the next write");
+                break;
+            }
+        }
+
+        if let Err(e) = spool.remove(spooled).await {
+            errors.report(&e, "stopping replay"); // Carrying on would write this batch twice
+            break;
+        }
+    }
+
+    if sent > 0 {
+        eprintln!("Replayed {} spooled batches to InfluxDB", sent);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::collections::VecDeque;
+    use std::sync::Mutex;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    // Stands in for InfluxDB, answering writes with the scripted statuses then 204
+    struct StandIn {
+        url: String,
+        writes: Arc<Mutex<Vec<String>>>,
+    }
+
+    async fn stand_in(statuses: &[u16]) -> StandIn {
+        slow_stand_in(statuses, Duration::ZERO).await
+    }
+
+    // The same, taking `delay` to answer each write
+    async fn slow_stand_in(statuses: &[u16], delay: Duration) -> StandIn {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let writes = Arc::new(Mutex::new(Vec::new()));
+        let statuses = Arc::new(Mutex::new(statuses.iter().copied().collect::<VecDeque<u16>>()));
+
+        let received = Arc::clone(&writes);
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut request = Vec::new();
+                let mut buffer = [0; 4096];
+                // Headers, then as much body as Content-Length says
+                let body = loop {
+                    let read = socket.read(&mut buffer).await.unwrap();
+                    request.extend_from_slice(&buffer[..read]);
+                    let text = String::from_utf8_lossy(&request).to_string();
+                    if let Some((headers, body)) = text.split_once("\r\n\r\n") {
+                        let length = headers
+                            .lines()
+                            .map(str::to_ascii_lowercase)
+                            .find_map(|line| line.strip_prefix("content-length: ")?.trim().parse().ok())
+                            .unwrap_or(0);
+                        if body.len() >= length || read == 0 {
+                            break body.to_string();
+                        }
+                    }
+                };
+
+                received.lock().unwrap().push(body);
+                tokio::time::sleep(delay).await;
+                let status = statuses.lock().unwrap().pop_front().unwrap_or(204);
+                let response =
+                    format!("HTTP/1.1 {} Stand-in\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status);
+                let _ = socket.write_all(response.as_bytes()).await;
+            }
+        });
+
+        StandIn { url, writes }
+    }
+
+    // Stands in for a sensor API, answering every request with the reading after a delay
+    async fn sensor(reading: Value, delay: Duration) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/sensor_data", listener.local_addr().unwrap());
+
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let body = reading.to_string();
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    let mut buffer = [0; 1024];
+                    while !request.windows(4).any(|end| end == b"\r\n\r\n") {
+                        match socket.read(&mut buffer).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(read) => request.extend_from_slice(&buffer[..read]),
+                        }
+                    }
+                    tokio::time::sleep(delay).await;
+                    let response = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                        body.len(),
+                        body
+                    );
+                    let _ = socket.write_all(response.as_bytes()).await;
+                });
+            }
+        });
+
+        url
+    }
+
+    fn influxdb(url: &str) -> InfluxDb {
+        let config: Config = serde_json::from_value(json!({
+            "endpoints": [],
+            "influxdb_url": url,
+            "influxdb_api_key": "key",
+            "influxdb_org": "org",
+            "influxdb_bucket": "bucket",
+            "query_interval": 1,
+            "request_timeout_s": 1,
+            "write_timeout_s": 5,
+        }))
+        .unwrap();
+        InfluxDb::new(Client::new(), &config).unwrap()
+    }
+
+    async fn spool_with(name: &str, batches: &[&str]) -> (Spool, std::path::PathBuf) {
+        let dir = std::env::temp_dir().join(format!("collector-deliver-{}-{}", name, process::id()));
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+        let spool = Spool::new(&dir.display().to_string(), 1024 * 1024, 3600).await;
+        for batch in batches {
+            spool.push(batch).await.unwrap();
+        }
+        (spool, dir)
+    }
 
-            //log_info(&format!("Successfully fetched sensor data from {} and sent log to InfluxDB", api_url));
+    async fn spooled(spool: &Spool) -> Vec<String> {
+        let mut contents = Vec::new();
+        for batch in spool.pending().await {
+            contents.push(spool.read(&batch).await.unwrap());
         }
+        contents
+    }
+
+    fn writes(stand_in: &StandIn) -> Vec<String> {
+        stand_in.writes.lock().unwrap().clone()
+    }
+
+    #[tokio::test]
+    async fn batch_is_written_straight_away_when_nothing_is_spooled() {
+        let stand_in = stand_in(&[]).await;
+        let (spool, dir) = spool_with("direct", &[]).await;
+
+        deliver(&influxdb(&stand_in.url), &spool, &mut ErrorCounts::default(), String::from("a")).await;
+
+        assert_eq!(writes(&stand_in), ["a"]);
+        assert!(spool.is_empty().await);
+        tokio::fs::remove_dir_all(dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn batch_is_spooled_once_retries_run_out() {
+        let stand_in = stand_in(&[503; 8]).await;
+        let (spool, dir) = spool_with("spooled", &[]).await;
+
+        deliver(&influxdb(&stand_in.url), &spool, &mut ErrorCounts::default(), String::from("a")).await;
+
+        assert_eq!(writes(&stand_in), ["a"; 5]); // The first try and 4 retries
+        assert_eq!(spooled(&spool).await, ["a"]);
+        tokio::fs::remove_dir_all(dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn writes_get_write_timeout_s_rather_than_request_timeout_s() {
+        let stand_in = slow_stand_in(&[], Duration::from_millis(1500)).await;
+        let (spool, dir) = spool_with("slow", &[]).await;
+
+        deliver(&influxdb(&stand_in.url), &spool, &mut ErrorCounts::default(), String::from("a")).await;
+
+        assert_eq!(writes(&stand_in), ["a"]);
+        assert!(spool.is_empty().await);
+        tokio::fs::remove_dir_all(dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejected_batch_is_dropped_without_retrying() {
+        let stand_in = stand_in(&[400]).await;
+        let (spool, dir) = spool_with("rejected", &[]).await;
+
+        deliver(&influxdb(&stand_in.url), &spool, &mut ErrorCounts::default(), String::from("a")).await;
+
+        assert_eq!(writes(&stand_in), ["a"]);
+        assert!(spool.is_empty().await);
+        tokio::fs::remove_dir_all(dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn spooled_batches_are_replayed_oldest_first() {
+        let stand_in = stand_in(&[]).await;
+        let (spool, dir) = spool_with("replay", &["a", "b"]).await;
+
+        deliver(&influxdb(&stand_in.url), &spool, &mut ErrorCounts::default(), String::from("c")).await;
+
+        assert_eq!(writes(&stand_in), ["a", "b", "c"]);
+        assert!(spool.is_empty().await);
+        tokio::fs::remove_dir_all(dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn replay_stops_at_the_first_retryable_failure() {
+        let stand_in = stand_in(&[204, 503]).await;
+        let (spool, dir) = spool_with("stop", &["a", "b"]).await;
+
+        deliver(&influxdb(&stand_in.url), &spool, &mut ErrorCounts::default(), String::from("c")).await;
+
+        // "a" is written, "b" fails, and "c" waits behind it so the order is kept
+        assert_eq!(writes(&stand_in), ["a", "b"]);
+        assert_eq!(spooled(&spool).await, ["b", "c"]);
+
+        // The next delivery picks up where this one stopped
+        deliver(&influxdb(&stand_in.url), &spool, &mut ErrorCounts::default(), String::from("d")).await;
+        assert_eq!(writes(&stand_in), ["a", "b", "b", "c", "d"]);
+        assert!(spool.is_empty().await);
+        tokio::fs::remove_dir_all(dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejected_spooled_batch_is_dropped_and_replay_carries_on() {
+        let stand_in = stand_in(&[422]).await;
+        let (spool, dir) = spool_with("skip", &["bad", "b"]).await;
+
+        deliver(&influxdb(&stand_in.url), &spool, &mut ErrorCounts::default(), String::from("c")).await;
+
+        assert_eq!(writes(&stand_in), ["bad", "b", "c"]);
+        assert!(spool.is_empty().await);
+        tokio::fs::remove_dir_all(dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn readings_in_one_batch_share_the_fallback_timestamp() {
+        let fast = sensor(json!({ "model": "BH1750", "lux": 312.5 }), Duration::ZERO).await;
+        let slow = sensor(json!({ "model": "SCD-41", "co2": 612 }), Duration::from_millis(1500)).await;
+        let config: Config = serde_json::from_value(json!({
+            "endpoints": [fast, slow],
+            "influxdb_url": "http://localhost:8086",
+            "influxdb_api_key": "key",
+            "influxdb_org": "org",
+            "influxdb_bucket": "bucket",
+            "query_interval": 60,
+        }))
+        .unwrap();
+        let config = Arc::new(config);
+
+        let (readings, mut received) = mpsc::channel(READINGS_CAPACITY);
+        for endpoint in &config.endpoints {
+            tokio::spawn(poll(Client::new(), Arc::clone(&config), endpoint.clone(), readings.clone()));
+        }
+        let mut batch = Vec::new();
+        for _ in &config.endpoints {
+            batch.push(timeout(Duration::from_secs(5), received.recv()).await.unwrap().unwrap());
+        }
+
+        // The slow sensor answered well after the fast one, yet both points get the batch's time
+        let collected_at = Utc::now();
+        let lines = encode_batch(&batch, "pi", Precision::Ms, collected_at, &mut ErrorCounts::default());
+        let timestamp = format!(" {}", collected_at.timestamp_millis());
+        assert_eq!(lines.len(), 2);
+        assert!(lines.iter().all(|line| line.ends_with(&timestamp)), "{:?}", lines);
     }
-}
\ No newline at end of file
+}
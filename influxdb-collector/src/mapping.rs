@@ -0,0 +1,55 @@
+use serde_json::{Map, Number, Value};
+
+use crate::config::Endpoint;
+
+// The endpoint's measurement, or the sensor's model
+pub fn measurement(endpoint: &Endpoint, sensor_data: &Value) -> String {
+    endpoint
+        .measurement
+        .clone()
+        .or_else(|| sensor_data.get("model").and_then(Value::as_str).map(String::from))
+        .unwrap_or_else(|| String::from("unknown"))
+}
+
+// Reshape a reading with the endpoint's field settings before it is encoded. Fields are
+// filtered and scaled by the names the sensor uses, then renamed, then the static tags go on top.
+pub fn map_fields(endpoint: &Endpoint, sensor_data: Value) -> Value {
+    let Value::Object(reading) = sensor_data else {
+        return sensor_data; // encode reports it
+    };
+
+    let mut mapped = Map::new();
+    for (key, value) in reading {
+        let value = match value {
+            _ if key == "timestamp" => value,
+            Value::Number(_) | Value::Bool(_) => {
+                if !endpoint.include.is_empty() && !endpoint.include.contains(&key) {
+                    continue;
+                }
+                if endpoint.exclude.contains(&key) {
+                    continue;
+                }
+                match (&value, endpoint.scale.get(&key)) {
+                    (Value::Number(n), Some(scale)) => {
+                        let scaled = n.as_f64().map(|n| n * scale.factor + scale.offset);
+                        match scaled.and_then(Number::from_f64) {
+                            Some(scaled) => Value::Number(scaled),
+                            None => continue, // Not finite, InfluxDB can't store it
+                        }
+                    }
+                    _ => value,
+                }
+            }
+            _ => value,
+        };
+
+        let key = endpoint.rename.get(&key).cloned().unwrap_or(key);
+        mapped.insert(key, value);
+    }
+
+    for (key, value) in &endpoint.tags {
+        mapped.insert(key.clone(), Value::String(value.clone()));
+    }
+
+    Value::Object(mapped)
+}
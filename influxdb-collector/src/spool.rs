@@ -0,0 +1,232 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+
+use crate::error::CollectorError;
+
+const EXTENSION: &str = "lp";
+
+// Batches that couldn't be written to InfluxDB, kept on disk until it is reachable again.
+// Each batch is a file named <unix millis>-<sequence>.lp, so sorting by name replays them in
+// the order they were collected. The points carry their own timestamps, replaying doesn't
+// move them.
+pub struct Spool {
+    dir: PathBuf,
+    max_bytes: u64,
+    max_age: Duration,
+    sequence: AtomicU64, // Keeps names unique when two batches land in the same millisecond
+}
+
+// A batch waiting on disk, removed once it has been written
+pub struct Batch {
+    path: PathBuf,
+    size: u64,
+    created_ms: u64,
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+impl Spool {
+    pub async fn new(dir: &str, max_bytes: u64, max_age_s: u64) -> Self {
+        if let Err(e) = fs::create_dir_all(dir).await {
+            eprintln!("Failed to create spool directory {}: {}", dir, e);
+        }
+
+        Spool {
+            dir: PathBuf::from(dir),
+            max_bytes,
+            max_age: Duration::from_secs(max_age_s),
+            sequence: AtomicU64::new(0),
+        }
+    }
+
+    // Oldest first, anything that isn't a finished batch file is ignored
+    async fn batches(&self) -> Vec<Batch> {
+        let mut batches = Vec::new();
+        let mut entries = match fs::read_dir(&self.dir).await {
+            Ok(entries) => entries,
+            Err(_) => return batches,
+        };
+
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let path = entry.path();
+            if path.extension().is_none_or(|extension| extension != EXTENSION) {
+                continue;
+            }
+            let created_ms = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.split('-').next())
+                .and_then(|millis| millis.parse().ok());
+            let size = entry.metadata().await.map(|metadata| metadata.len());
+            if let (Some(created_ms), Ok(size)) = (created_ms, size) {
+                batches.push(Batch { path, size, created_ms });
+            }
+        }
+
+        batches.sort_by(|a, b| a.path.cmp(&b.path));
+        batches
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        self.batches().await.is_empty()
+    }
+
+    // Append a batch, then drop the oldest ones if the spool is over its limits
+    pub async fn push(&self, batch: &str) -> Result<(), CollectorError> {
+        let sequence = self.sequence.fetch_add(1, Ordering::Relaxed);
+        let name = format!("{:020}-{:06}", now_ms(), sequence % 1_000_000);
+        let path = self.dir.join(format!("{}.{}", name, EXTENSION));
+        let partial = self.dir.join(format!("{}.tmp", name));
+
+        // Written under another name first, a crash mid-write mustn't leave half a batch to replay
+        let written = async {
+            let mut file = fs::File::create(&partial).await?;
+            file.write_all(batch.as_bytes()).await?;
+            file.sync_all().await?;
+            fs::rename(&partial, &path).await
+        };
+        written.await.map_err(|e| self.error(&path, "Failed to write batch", e))?;
+
+        self.enforce_limits().await;
+        Ok(())
+    }
+
+    async fn enforce_limits(&self) {
+        let oldest_ms = now_ms().saturating_sub(self.max_age.as_millis() as u64);
+        let mut batches = self.batches().await;
+        let mut total: u64 = batches.iter().map(|batch| batch.size).sum();
+        let mut dropped = 0;
+
+        batches.reverse(); // Pop from the oldest end
+        while let Some(batch) = batches.pop() {
+            if batch.created_ms >= oldest_ms && total <= self.max_bytes {
+                break;
+            }
+            if fs::remove_file(&batch.path).await.is_ok() {
+                total -= batch.size;
+                dropped += 1;
+            }
+        }
+
+        if dropped > 0 {
+            eprintln!("Spool over its size or age limit, dropped the {} oldest batches", dropped);
+        }
+    }
+
+    // Spooled batches oldest first, after dropping any that are over the limits
+    pub async fn pending(&self) -> Vec<Batch> {
+        self.enforce_limits().await;
+        self.batches().await
+    }
+
+    pub async fn read(&self, batch: &Batch) -> Result<String, CollectorError> {
+        fs::read_to_string(&batch.path)
+            .await
+            .map_err(|e| self.error(&batch.path, "Failed to read batch", e))
+    }
+
+    pub async fn remove(&self, batch: Batch) -> Result<(), CollectorError> {
+        fs::remove_file(&batch.path)
+            .await
+            .map_err(|e| self.error(&batch.path, "Failed to remove batch", e))
+    }
+
+    // Errors are counted against the spool directory, named with the batch file
+    fn error(&self, path: &Path, action: &str, e: std::io::Error) -> CollectorError {
+        CollectorError::Spool {
+            path: self.dir.display().to_string(),
+            message: format!("{} {}: {}", action, path.display(), e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A fresh directory per test, tests run in parallel
+    async fn spool_in(name: &str, max_bytes: u64, max_age_s: u64) -> (Spool, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("collector-spool-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir).await;
+        let spool = Spool::new(&dir.display().to_string(), max_bytes, max_age_s).await;
+        (spool, dir)
+    }
+
+    async fn contents(spool: &Spool) -> Vec<String> {
+        let mut contents = Vec::new();
+        for batch in spool.pending().await {
+            contents.push(spool.read(&batch).await.unwrap());
+        }
+        contents
+    }
+
+    #[tokio::test]
+    async fn pending_returns_batches_in_the_order_they_were_pushed() {
+        let (spool, dir) = spool_in("order", 1024, 3600).await;
+        assert!(spool.is_empty().await);
+
+        for batch in ["first", "second", "third"] {
+            spool.push(batch).await.unwrap();
+        }
+        assert!(!spool.is_empty().await);
+        assert_eq!(contents(&spool).await, ["first", "second", "third"]);
+
+        let oldest = spool.pending().await.remove(0);
+        spool.remove(oldest).await.unwrap();
+        assert_eq!(contents(&spool).await, ["second", "third"]);
+
+        fs::remove_dir_all(dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn oldest_batches_are_dropped_over_the_size_limit() {
+        let (spool, dir) = spool_in("size", 10, 3600).await;
+
+        for batch in ["aaaaaa", "bbbbbb", "cccccc"] {
+            spool.push(batch).await.unwrap();
+        }
+        // 18 bytes is over the limit, dropping "aaaaaa" still leaves 12
+        assert_eq!(contents(&spool).await, ["cccccc"]);
+
+        fs::remove_dir_all(dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn batches_older_than_the_age_limit_are_dropped() {
+        let (spool, dir) = spool_in("age", 1024, 60).await;
+
+        let two_minutes_ago = now_ms() - 120_000;
+        fs::write(dir.join(format!("{:020}-000000.lp", two_minutes_ago)), "stale").await.unwrap();
+        spool.push("fresh").await.unwrap();
+
+        assert_eq!(contents(&spool).await, ["fresh"]);
+
+        fs::remove_dir_all(dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn unfinished_and_unrelated_files_are_ignored() {
+        let (spool, dir) = spool_in("ignored", 1024, 3600).await;
+
+        // Left behind by a crash mid-write, a minute ago
+        let name = format!("{:020}-000000", now_ms() - 60_000);
+        fs::write(dir.join(format!("{}.tmp", name)), "half a batch").await.unwrap();
+        fs::write(dir.join("notes.txt"), "not a batch").await.unwrap();
+        fs::write(dir.join("not-a-time.lp"), "not a batch").await.unwrap();
+        assert!(spool.is_empty().await);
+
+        spool.push("batch").await.unwrap();
+        assert_eq!(contents(&spool).await, ["batch"]);
+        assert!(dir.join(format!("{}.tmp", name)).exists());
+
+        fs::remove_dir_all(dir).await.unwrap();
+    }
+}
@@ -1,10 +1,173 @@
 use embedded_hal::i2c::I2c;
 use log::{info, error};
+use serde::{Deserialize, Serialize};
 use std::fmt::Debug;
+use std::time::{Duration, Instant};
+
+const MAIN_CTRL_ENABLE: u8 = 0x02; // ALS/UVS enable
+const MAIN_CTRL_UVS_MODE: u8 = 0x08; // Set for UVS, clear for ALS
+const MAIN_STATUS_DATA_READY: u8 = 0x08; // New ALS/UVS data, cleared when read
+const DATA_MASK: u32 = 0xF_FFFF; // Results are 20 bit, the top nibble of the MSB is reserved
+
+// UV counts per UV index at gain 18 and 20 bit (400 ms), from the datasheet
+const UV_SENSITIVITY: f32 = 2300.0;
+
+// Which channel the sensor is converting
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    Als,
+    Uvs,
+}
+
+// Analog gain, stored in config.json as the multiplier
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(try_from = "u8", into = "u8")]
+pub enum Gain {
+    X1,
+    X3,
+    X6,
+    X9,
+    X18,
+}
+
+impl Gain {
+    fn bits(self) -> u8 {
+        match self {
+            Gain::X1 => 0b000,
+            Gain::X3 => 0b001,
+            Gain::X6 => 0b010,
+            Gain::X9 => 0b011,
+            Gain::X18 => 0b100,
+        }
+    }
+
+    pub fn factor(self) -> f32 {
+        u8::from(self) as f32
+    }
+}
+
+impl TryFrom<u8> for Gain {
+    type Error = String;
+
+    fn try_from(gain: u8) -> Result<Self, String> {
+        match gain {
+            1 => Ok(Gain::X1),
+            3 => Ok(Gain::X3),
+            6 => Ok(Gain::X6),
+            9 => Ok(Gain::X9),
+            18 => Ok(Gain::X18),
+            _ => Err(format!("gain must be 1, 3, 6, 9 or 18, got {}", gain)),
+        }
+    }
+}
+
+impl From<Gain> for u8 {
+    fn from(gain: Gain) -> u8 {
+        match gain {
+            Gain::X1 => 1,
+            Gain::X3 => 3,
+            Gain::X6 => 6,
+            Gain::X9 => 9,
+            Gain::X18 => 18,
+        }
+    }
+}
+
+// ADC resolution, which also sets the conversion time. Stored in config.json as bits
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(try_from = "u8", into = "u8")]
+pub enum Resolution {
+    Bits20, // 400 ms
+    Bits19, // 200 ms
+    Bits18, // 100 ms
+    Bits17, // 50 ms
+    Bits16, // 25 ms
+    Bits13, // 12.5 ms
+}
+
+impl Resolution {
+    // Resolution bits [6:4] and a matching measurement rate [2:0] for ALS_UVS_MEAS_RATE
+    fn meas_rate(self) -> u8 {
+        let (resolution, rate) = match self {
+            Resolution::Bits20 => (0b000, 0b100), // 500 ms rate
+            Resolution::Bits19 => (0b001, 0b011), // 200 ms rate
+            Resolution::Bits18 => (0b010, 0b010), // 100 ms rate
+            Resolution::Bits17 => (0b011, 0b001), // 50 ms rate
+            Resolution::Bits16 => (0b100, 0b000), // 25 ms rate
+            Resolution::Bits13 => (0b101, 0b000), // 25 ms rate
+        };
+        (resolution << 4) | rate
+    }
+
+    pub fn integration_time_ms(self) -> f32 {
+        match self {
+            Resolution::Bits20 => 400.0,
+            Resolution::Bits19 => 200.0,
+            Resolution::Bits18 => 100.0,
+            Resolution::Bits17 => 50.0,
+            Resolution::Bits16 => 25.0,
+            Resolution::Bits13 => 12.5,
+        }
+    }
+
+    // Time between results at the measurement rate set alongside this resolution
+    fn measurement_period(self) -> Duration {
+        match self {
+            Resolution::Bits20 => Duration::from_millis(500),
+            Resolution::Bits19 => Duration::from_millis(200),
+            Resolution::Bits18 => Duration::from_millis(100),
+            Resolution::Bits17 => Duration::from_millis(50),
+            Resolution::Bits16 | Resolution::Bits13 => Duration::from_millis(25),
+        }
+    }
+}
+
+impl TryFrom<u8> for Resolution {
+    type Error = String;
+
+    fn try_from(bits: u8) -> Result<Self, String> {
+        match bits {
+            20 => Ok(Resolution::Bits20),
+            19 => Ok(Resolution::Bits19),
+            18 => Ok(Resolution::Bits18),
+            17 => Ok(Resolution::Bits17),
+            16 => Ok(Resolution::Bits16),
+            13 => Ok(Resolution::Bits13),
+            _ => Err(format!("resolution must be 20, 19, 18, 17, 16 or 13 bits, got {}", bits)),
+        }
+    }
+}
+
+impl From<Resolution> for u8 {
+    fn from(resolution: Resolution) -> u8 {
+        match resolution {
+            Resolution::Bits20 => 20,
+            Resolution::Bits19 => 19,
+            Resolution::Bits18 => 18,
+            Resolution::Bits17 => 17,
+            Resolution::Bits16 => 16,
+            Resolution::Bits13 => 13,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error<E> {
+    I2c(E),
+    NotReady, // No new data within a few measurement periods
+}
+
+impl<E> From<E> for Error<E> {
+    fn from(e: E) -> Self {
+        Error::I2c(e)
+    }
+}
 
 pub struct LTR390<I2C> {
     i2c: I2C,
     address: u8,
+    gain: Gain,
+    resolution: Resolution,
 }
 
 impl<I2C, E> LTR390<I2C>
@@ -13,7 +176,12 @@ where
     E: Debug,
 {
     pub fn new(i2c: I2C, address: u8) -> Self {
-        LTR390 { i2c, address }
+        LTR390 {
+            i2c,
+            address,
+            gain: Gain::X3, // Power on defaults
+            resolution: Resolution::Bits18,
+        }
     }
 
     pub fn begin(&mut self) -> Result<bool, E> {
@@ -79,16 +247,92 @@ where
     pub fn enable(&mut self, en: bool) -> Result<(), E> {
         let mut ctrl = self.read_register(0x00)?; // LTR390_MAIN_CTRL
         if en {
-            ctrl |= 0x02;
+            ctrl |= MAIN_CTRL_ENABLE;
         } else {
-            ctrl &= !0x02;
+            ctrl &= !MAIN_CTRL_ENABLE;
         }
         self.write_register(0x00, ctrl) // LTR390_MAIN_CTRL
     }
 
     pub fn enabled(&mut self) -> Result<bool, E> {
         let ctrl = self.read_register(0x00)?; // LTR390_MAIN_CTRL
-        Ok((ctrl & 0x02) != 0)
+        Ok((ctrl & MAIN_CTRL_ENABLE) != 0)
+    }
+
+    pub fn set_mode(&mut self, mode: Mode) -> Result<(), E> {
+        let mut ctrl = self.read_register(0x00)?; // LTR390_MAIN_CTRL
+        match mode {
+            Mode::Uvs => ctrl |= MAIN_CTRL_UVS_MODE,
+            Mode::Als => ctrl &= !MAIN_CTRL_UVS_MODE,
+        }
+        self.write_register(0x00, ctrl) // LTR390_MAIN_CTRL
+    }
+
+    pub fn set_gain(&mut self, gain: Gain) -> Result<(), E> {
+        self.write_register(0x05, gain.bits())?; // LTR390_GAIN
+        self.gain = gain;
+        Ok(())
+    }
+
+    pub fn set_resolution(&mut self, resolution: Resolution) -> Result<(), E> {
+        self.write_register(0x04, resolution.meas_rate())?; // LTR390_MEAS_RATE
+        self.resolution = resolution;
+        Ok(())
+    }
+
+    pub fn gain(&self) -> Gain {
+        self.gain
+    }
+
+    pub fn resolution(&self) -> Resolution {
+        self.resolution
+    }
+
+    pub fn data_ready(&mut self) -> Result<bool, E> {
+        let status = self.read_register(0x07)?; // LTR390_MAIN_STATUS
+        Ok((status & MAIN_STATUS_DATA_READY) != 0)
+    }
+
+    // Switch to the given channel and wait for a conversion made in that mode
+    pub fn measure(&mut self, mode: Mode) -> Result<u32, Error<E>> {
+        self.set_mode(mode)?;
+
+        // Reading the status clears the flag, so a stale result from the other mode is dropped
+        self.data_ready()?;
+
+        // A conversion already running in the old mode can finish first, wait for the one after it
+        let period = self.resolution.measurement_period();
+        let deadline = Instant::now() + period * 4;
+        let mut conversions = 0;
+        while conversions < 2 {
+            if Instant::now() > deadline {
+                return Err(Error::NotReady);
+            }
+            std::thread::sleep(Duration::from_millis(5));
+            if self.data_ready()? {
+                conversions += 1;
+            }
+        }
+
+        let data = match mode {
+            Mode::Als => self.read_als()?,
+            Mode::Uvs => self.read_uvs()?,
+        };
+        Ok(data)
+    }
+
+    // lux = 0.6 * ALS / (gain * integration time / 100 ms) * window factor
+    pub fn als_to_lux(&self, als: u32, window_factor: f32) -> f32 {
+        let int = self.resolution.integration_time_ms() / 100.0;
+        0.6 * als as f32 / (self.gain.factor() * int) * window_factor
+    }
+
+    // UVI = UVS / sensitivity * window factor, sensitivity scaled from gain 18 at 400 ms
+    pub fn uvs_to_uv_index(&self, uvs: u32, window_factor: f32) -> f32 {
+        let sensitivity = UV_SENSITIVITY
+            * (self.gain.factor() / 18.0)
+            * (self.resolution.integration_time_ms() / 400.0);
+        uvs as f32 / sensitivity * window_factor
     }
 
     fn read_register(&mut self, reg: u8) -> Result<u8, E> {
@@ -108,6 +352,41 @@ where
         for (i, &b) in buf.iter().enumerate() {
             value |= (b as u32) << (8 * i);
         }
-        Ok(value)
+        Ok(value & DATA_MASK)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_hal::i2c::{ErrorType, Operation};
+    use std::convert::Infallible;
+
+    // Answers every read with the same bytes
+    struct FakeBus([u8; 3]);
+
+    impl ErrorType for FakeBus {
+        type Error = Infallible;
+    }
+
+    impl I2c for FakeBus {
+        fn transaction(&mut self, _address: u8, operations: &mut [Operation<'_>]) -> Result<(), Infallible> {
+            for operation in operations {
+                if let Operation::Read(buffer) = operation {
+                    buffer.copy_from_slice(&self.0[..buffer.len()]);
+                }
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn data_registers_are_little_endian_20_bit() {
+        let mut ltr390 = LTR390::new(FakeBus([0x56, 0x34, 0x02]), 0x53);
+        assert_eq!(ltr390.read_als().unwrap(), 0x2_3456);
+
+        // Reserved bits in the MSB don't leak into the count
+        let mut ltr390 = LTR390::new(FakeBus([0xFF, 0xFF, 0xFF]), 0x53);
+        assert_eq!(ltr390.read_uvs().unwrap(), 0xF_FFFF);
+    }
+}
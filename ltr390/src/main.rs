@@ -1,122 +1,153 @@
 mod ltr390;
 
-use actix_web::{web, App, HttpServer, HttpResponse, Responder, middleware::Logger}; // Import necessary Actix Web components
 use linux_embedded_hal::I2cdev;  // Import I2C device from linux_embedded_hal
 use serde::{Deserialize, Serialize}; // Import serialization/deserialization from Serde
-use chrono::Utc; // Import Utc for timestamps
-use std::fs; // Import filesystem operations
-use std::fs::File; // Import file operations
-use std::io::Write; // Import write operations
-use env_logger::Env; // Import environment logger
-use ltr390::LTR390; // Import LTR390 driver
+use sensor_core::{Sensor, SensorConfig, SensorError, ServerConfig}; // Import shared sensor server
+use ltr390::{Gain, Mode, Resolution, LTR390}; // Import LTR390 driver
 
 // Configuration structure for the application
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 struct Config {
-    network_port: u16, // Port for the web server
+    #[serde(flatten)]
+    server: ServerConfig, // Port, bind address and I2C bus path
     i2c_address_decimal: u16, // I2C address of the LTR390 sensor
-    i2c_bus_device_path: String, // Path to the I2C bus device
-    bind_address: String, // Address to bind the web server to
+    #[serde(default = "default_gain")]
+    gain: Gain, // Analog gain: 1, 3, 6, 9 or 18
+    #[serde(default = "default_resolution")]
+    resolution_bits: Resolution, // 20, 19, 18, 17, 16 or 13, more bits means longer conversions
+    #[serde(default = "default_window_factor")]
+    window_factor: f32, // Compensation for a cover over the sensor, 1.0 without one
+}
+
+fn default_gain() -> Gain {
+    Gain::X3 // Sensor power on default
+}
+
+fn default_resolution() -> Resolution {
+    Resolution::Bits18 // 100 ms conversions
+}
+
+fn default_window_factor() -> f32 {
+    1.0 // No window
 }
 
 // Default implementation for the Config struct
 impl Default for Config {
     fn default() -> Self {
         Config {
-            network_port: 5003, // Default network port
+            server: ServerConfig::with_port(5003), // Default network port
             i2c_address_decimal: 0x53, // Default I2C address (83 in decimal)
-            i2c_bus_device_path: String::from("/dev/i2c-1"), // Default I2C bus device path
-            bind_address: String::from("0.0.0.0"), // Default bind address
+            gain: default_gain(),
+            resolution_bits: default_resolution(),
+            window_factor: default_window_factor(),
         }
     }
 }
 
-// Function to read the configuration from a file or create a default one if it doesn't exist
-fn read_or_create_config() -> Config {
-    let config_path = "config.json";
-    if let Ok(config_data) = fs::read_to_string(config_path) {
-        if let Ok(config) = serde_json::from_str(&config_data) {
-            return config;
-        }
+impl SensorConfig for Config {
+    fn server(&self) -> &ServerConfig {
+        &self.server
     }
 
-    let default_config = Config::default();
-    let config_data = serde_json::to_string_pretty(&default_config).unwrap();
-    let mut file = File::create(config_path).unwrap();
-    file.write_all(config_data.as_bytes()).unwrap();
-    default_config
+    fn i2c_address(&self) -> Option<u16> {
+        Some(self.i2c_address_decimal)
+    }
 }
 
 // Structure to hold sensor data
 #[derive(Serialize)]
 struct SensorData {
-    timestamp: String,
-    model: String,
     uv_index: f32,
-    ambient_light: f32,
+    lux: f32,
+    raw_uvs: u32,
+    raw_als: u32,
+    gain: u8,
+    resolution_bits: u8,
 }
 
-async fn get_sensor_data() -> impl Responder {
-    let config = read_or_create_config();
+struct Ltr390Sensor {
+    ltr390: LTR390<I2cdev>,
+    window_factor: f32,
+}
+
+impl Sensor for Ltr390Sensor {
+    type Config = Config;
+    type Reading = SensorData;
+
+    const MODEL: &'static str = "LTR390";
 
-    // Set up the I2C bus and LTR390 sensor
-    let i2c_bus = match I2cdev::new(&config.i2c_bus_device_path) {
-        Ok(bus) => bus,
-        Err(e) => {
-            eprintln!("Failed to open I2C bus: {:?}", e);
-            return HttpResponse::InternalServerError().body("Failed to open I2C bus");
+    fn init(config: &Config) -> Result<Self, SensorError> {
+        // Set up the I2C bus and LTR390 sensor
+        let i2c_bus = I2cdev::new(&config.server.i2c_bus_device_path).map_err(SensorError::bus)?;
+
+        // Create LTR390 sensor object with the correct I2C address
+        let mut ltr390 = LTR390::new(i2c_bus, config.i2c_address_decimal as u8);
+
+        // Initialize the LTR390 sensor
+        if !ltr390.begin().map_err(SensorError::i2c)? {
+            return Err(SensorError::NotFound(String::from("LTR390 did not respond as expected")));
         }
-    };
 
-    // Create LTR390 sensor object with the correct I2C address
-    let mut ltr390 = LTR390::new(i2c_bus, config.i2c_address_decimal as u8);
+        // Apply gain and resolution, these are shared by the ALS and UVS channels
+        ltr390.set_gain(config.gain).map_err(SensorError::i2c)?;
+        ltr390.set_resolution(config.resolution_bits).map_err(SensorError::i2c)?;
 
-    // Initialize the LTR390 sensor
-    if let Err(e) = ltr390.begin() {
-        eprintln!("Failed to initialize LTR390 sensor: {:?}", e);
-        return HttpResponse::InternalServerError().body("Failed to initialize LTR390 sensor");
+        Ok(Ltr390Sensor {
+            ltr390,
+            window_factor: config.window_factor,
+        })
     }
 
-    // Read sensor data
-    let uv_data = match ltr390.read_uvs() {
-        Ok(data) => data,
-        Err(e) => {
-            eprintln!("Failed to read UV sensor data: {:?}", e);
-            return HttpResponse::InternalServerError().body("Failed to read UV sensor data");
-        }
-    };
+    fn read(&mut self) -> Result<SensorData, SensorError> {
+        // The sensor converts one channel at a time, so measure UVS then ALS
+        let raw_uvs = self.ltr390.measure(Mode::Uvs)?;
+        let raw_als = self.ltr390.measure(Mode::Als)?;
+
+        Ok(SensorData {
+            uv_index: self.ltr390.uvs_to_uv_index(raw_uvs, self.window_factor),
+            lux: self.ltr390.als_to_lux(raw_als, self.window_factor),
+            raw_uvs,
+            raw_als,
+            gain: self.ltr390.gain().into(),
+            resolution_bits: self.ltr390.resolution().into(),
+        })
+    }
+}
 
-    let als_data = match ltr390.read_als() {
-        Ok(data) => data,
-        Err(e) => {
-            eprintln!("Failed to read ALS sensor data: {:?}", e);
-            return HttpResponse::InternalServerError().body("Failed to read ALS sensor data");
+// Sort driver errors into the shared error codes
+impl<E: embedded_hal::i2c::Error> From<ltr390::Error<E>> for SensorError {
+    fn from(e: ltr390::Error<E>) -> Self {
+        match e {
+            ltr390::Error::I2c(e) => SensorError::i2c(e),
+            ltr390::Error::NotReady => SensorError::Timeout(String::from("No new data from the LTR390")),
         }
-    };
-
-    // Create sensor data response
-    let sensor_data = SensorData {
-        timestamp: Utc::now().to_rfc3339(),
-        model: String::from("LTR390"),
-        uv_index: uv_data as f32,
-        ambient_light: als_data as f32,
-    };
+    }
+}
 
-    HttpResponse::Ok().json(sensor_data)
+fn main() -> std::io::Result<()> {
+    sensor_core::run::<Ltr390Sensor>()
 }
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    env_logger::init_from_env(Env::default().default_filter_or("info"));
-
-    let config = read_or_create_config();
-
-    HttpServer::new(|| {
-        App::new()
-            .wrap(Logger::default())
-            .route("/sensor_data", web::get().to(get_sensor_data))
-    })
-    .bind((config.bind_address.as_str(), config.network_port))? // Use bind_address from config
-    .run()
-    .await
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_from_before_gain_and_resolution_keeps_its_settings() {
+        let config: Config = serde_json::from_str(
+            r#"{
+                "network_port": 6003,
+                "i2c_bus_device_path": "/dev/i2c-3",
+                "bind_address": "0.0.0.0",
+                "i2c_address_decimal": 83
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(config.server.network_port, 6003);
+        assert_eq!(config.server.i2c_bus_device_path, "/dev/i2c-3");
+        assert_eq!(config.gain, Gain::X3);
+        assert_eq!(config.resolution_bits, Resolution::Bits18);
+        assert_eq!(config.window_factor, 1.0);
+    }
+}
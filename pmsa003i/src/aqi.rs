@@ -0,0 +1,160 @@
+// Air quality indices from particulate concentrations in µg/m³
+
+// (concentration low, concentration high, index low, index high)
+type Breakpoint = (f32, f32, f32, f32);
+
+// US EPA PM2.5 breakpoints, as revised in 2024
+const EPA_PM2_5: [Breakpoint; 6] = [
+    (0.0, 9.0, 0.0, 50.0),
+    (9.1, 35.4, 51.0, 100.0),
+    (35.5, 55.4, 101.0, 150.0),
+    (55.5, 125.4, 151.0, 200.0),
+    (125.5, 225.4, 201.0, 300.0),
+    (225.5, 325.4, 301.0, 500.0),
+];
+
+// US EPA PM10 breakpoints
+const EPA_PM10: [Breakpoint; 6] = [
+    (0.0, 54.0, 0.0, 50.0),
+    (55.0, 154.0, 51.0, 100.0),
+    (155.0, 254.0, 101.0, 150.0),
+    (255.0, 354.0, 151.0, 200.0),
+    (355.0, 424.0, 201.0, 300.0),
+    (425.0, 604.0, 301.0, 500.0),
+];
+
+// EU CAQI hourly background grid, the last band is open ended
+const CAQI_PM2_5: [Breakpoint; 4] = [
+    (0.0, 15.0, 0.0, 25.0),
+    (15.0, 30.0, 25.0, 50.0),
+    (30.0, 55.0, 50.0, 75.0),
+    (55.0, 110.0, 75.0, 100.0),
+];
+
+const CAQI_PM10: [Breakpoint; 4] = [
+    (0.0, 25.0, 0.0, 25.0),
+    (25.0, 50.0, 25.0, 50.0),
+    (50.0, 90.0, 50.0, 75.0),
+    (90.0, 180.0, 75.0, 100.0),
+];
+
+// EPA AQI for PM2.5, concentration truncated to 0.1 µg/m³ and capped at 500
+pub fn epa_pm2_5(concentration: f32) -> u16 {
+    let c = (concentration * 10.0 + 1e-3).floor() / 10.0; // Nudge so 35.4 less a rounding error doesn't become 35.3
+    epa_index(&EPA_PM2_5, c)
+}
+
+// EPA AQI for PM10, concentration truncated to 1 µg/m³ and capped at 500
+pub fn epa_pm10(concentration: f32) -> u16 {
+    epa_index(&EPA_PM10, concentration.floor())
+}
+
+// CAQI is the worse of the two pollutant sub-indices
+pub fn caqi(pm2_5: f32, pm10: f32) -> u16 {
+    caqi_index(&CAQI_PM2_5, pm2_5).max(caqi_index(&CAQI_PM10, pm10))
+}
+
+fn epa_index(table: &[Breakpoint], c: f32) -> u16 {
+    let c = c.max(0.0);
+    match table.iter().find(|&&(_, c_hi, _, _)| c <= c_hi) {
+        Some(&band) => interpolate(band, c),
+        None => 500, // Beyond the index
+    }
+}
+
+fn caqi_index(table: &[Breakpoint], c: f32) -> u16 {
+    let c = c.max(0.0);
+    let last = table[table.len() - 1];
+    let band = table.iter().find(|&&(_, c_hi, _, _)| c <= c_hi).copied().unwrap_or(last);
+    // Above the grid keep the slope of the top band, anything over 100 is "very high"
+    interpolate(band, c)
+}
+
+fn interpolate((c_lo, c_hi, i_lo, i_hi): Breakpoint, c: f32) -> u16 {
+    ((i_hi - i_lo) / (c_hi - c_lo) * (c - c_lo) + i_lo).round() as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn epa_pm2_5_band_edges() {
+        assert_eq!(epa_pm2_5(0.0), 0);
+        assert_eq!(epa_pm2_5(9.0), 50);
+        assert_eq!(epa_pm2_5(9.1), 51);
+        assert_eq!(epa_pm2_5(35.4), 100);
+        assert_eq!(epa_pm2_5(35.5), 101);
+        assert_eq!(epa_pm2_5(55.4), 150);
+        assert_eq!(epa_pm2_5(55.5), 151);
+        assert_eq!(epa_pm2_5(125.4), 200);
+        assert_eq!(epa_pm2_5(125.5), 201);
+        assert_eq!(epa_pm2_5(225.4), 300);
+        assert_eq!(epa_pm2_5(225.5), 301);
+        assert_eq!(epa_pm2_5(325.4), 500);
+    }
+
+    #[test]
+    fn epa_pm2_5_above_the_index_is_500() {
+        assert_eq!(epa_pm2_5(325.5), 500);
+        assert_eq!(epa_pm2_5(1000.0), 500);
+    }
+
+    #[test]
+    fn epa_pm2_5_truncates_to_a_tenth() {
+        assert_eq!(epa_pm2_5(35.49), 100);
+        assert_eq!(epa_pm2_5(9.09), 50);
+        assert_eq!(epa_pm2_5(-1.0), 0);
+    }
+
+    #[test]
+    fn epa_pm2_5_nudge_keeps_values_just_under_a_tenth() {
+        // An average that should be 9.0 but came out one step below, without the nudge it
+        // would truncate to 8.9 and give 49
+        let just_under = f32::from_bits(9.0_f32.to_bits() - 1);
+        assert_eq!(epa_pm2_5(just_under), 50);
+        let just_under = f32::from_bits(35.4_f32.to_bits() - 1);
+        assert_eq!(epa_pm2_5(just_under), 100);
+    }
+
+    #[test]
+    fn epa_pm10_band_edges() {
+        assert_eq!(epa_pm10(0.0), 0);
+        assert_eq!(epa_pm10(54.0), 50);
+        assert_eq!(epa_pm10(54.9), 50);
+        assert_eq!(epa_pm10(55.0), 51);
+        assert_eq!(epa_pm10(154.0), 100);
+        assert_eq!(epa_pm10(155.0), 101);
+        assert_eq!(epa_pm10(424.0), 300);
+        assert_eq!(epa_pm10(425.0), 301);
+        assert_eq!(epa_pm10(604.0), 500);
+        assert_eq!(epa_pm10(605.0), 500);
+    }
+
+    #[test]
+    fn caqi_band_edges() {
+        assert_eq!(caqi(0.0, 0.0), 0);
+        assert_eq!(caqi(15.0, 0.0), 25);
+        assert_eq!(caqi(30.0, 0.0), 50);
+        assert_eq!(caqi(55.0, 0.0), 75);
+        assert_eq!(caqi(110.0, 0.0), 100);
+        assert_eq!(caqi(0.0, 25.0), 25);
+        assert_eq!(caqi(0.0, 50.0), 50);
+        assert_eq!(caqi(0.0, 90.0), 75);
+        assert_eq!(caqi(0.0, 180.0), 100);
+    }
+
+    #[test]
+    fn caqi_is_open_ended_above_the_grid() {
+        // The top band's slope carries on, 25 per 55 µg/m³ for PM2.5 and 25 per 90 µg/m³ for PM10
+        assert_eq!(caqi(165.0, 0.0), 125);
+        assert_eq!(caqi(0.0, 270.0), 125);
+    }
+
+    #[test]
+    fn caqi_is_the_worse_sub_index() {
+        assert_eq!(caqi(30.0, 90.0), 75);
+        assert_eq!(caqi(55.0, 50.0), 75);
+        assert_eq!(caqi(7.5, 12.5), 13);
+    }
+}
@@ -1,98 +1,180 @@
-use actix_web::{web, App, HttpServer, HttpResponse, Responder, middleware::Logger};
-use env_logger::Env;
+mod aqi;
+mod pmsa003i;
+
 use serde::{Deserialize, Serialize};
-use chrono::Utc;
-//use std::fs;
-use std::fs::File;
-use std::io::Write;
 use linux_embedded_hal::I2cdev;
-use pmsa003i::Pmsa003i;
-
-#[derive(Serialize, Deserialize)]
+use pmsa003i::PMSA003I;
+use sensor_core::{Sensor, SensorConfig, SensorError, ServerConfig};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
 
+#[derive(Serialize, Deserialize, Clone)]
 struct Config {
-    network_port: u16,
-    i2c_bus_device_path: String,
-    bind_address: String,
+    #[serde(flatten)]
+    server: ServerConfig,
+    #[serde(default = "default_i2c_address")]
+    i2c_address_decimal: u16,
+    #[serde(default = "default_aqi_average_minutes")]
+    aqi_average_minutes: u64, // Rolling window the AQI is calculated over, EPA uses 24 hours
+    #[serde(default)]
+    include_caqi: bool, // Also report the European Common Air Quality Index
+}
+
+fn default_i2c_address() -> u16 {
+    0x12
+}
+
+fn default_aqi_average_minutes() -> u64 {
+    60
 }
 
 impl Default for Config {
     fn default() -> Self {
         Config {
-            network_port: 5001,
-            i2c_bus_device_path: String::from("/dev/i2c-1"),
-            bind_address: String::from("0.0.0.0"),
+            server: ServerConfig::with_port(5001),
+            i2c_address_decimal: default_i2c_address(),
+            aqi_average_minutes: default_aqi_average_minutes(),
+            include_caqi: false,
         }
     }
 }
 
-fn read_or_create_config() -> Config {
-    let config_path = "config.json";
-    if let Ok(file) = File::open(config_path) {
-        serde_json::from_reader(file).unwrap_or_else(|_| Config::default())
-    } else {
-        let default_config = Config::default();
-        let config_data = serde_json::to_string_pretty(&default_config).unwrap();
-        let mut file = File::create(config_path).unwrap();
-        file.write_all(config_data.as_bytes()).unwrap();
-        default_config
+impl SensorConfig for Config {
+    fn server(&self) -> &ServerConfig {
+        &self.server
+    }
+
+    fn i2c_address(&self) -> Option<u16> {
+        Some(self.i2c_address_decimal)
     }
 }
 
 #[derive(Serialize)]
 struct SensorData {
-    timestamp: String,
-    model: String,
     pm1_0: u16,
     pm2_5: u16,
-    pm10: u16,   
+    pm10: u16,
+    pm1_0_env: u16,
+    pm2_5_env: u16,
+    pm10_env: u16,
+    particles_03um: u16,
+    particles_05um: u16,
+    particles_10um: u16,
+    particles_25um: u16,
+    particles_50um: u16,
+    particles_100um: u16,
+    pm2_5_average: f32,
+    pm10_average: f32,
+    aqi: u16,
+    aqi_pm2_5: u16,
+    aqi_pm10: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    caqi: Option<u16>,
 }
 
-async fn get_sensor_data() -> impl Responder {
-    let config = read_or_create_config();
+struct Pmsa003iSensor {
+    pmsa003i: PMSA003I<I2cdev>,
+    history: VecDeque<(Instant, u16, u16)>, // Atmospheric PM2.5 and PM10 inside the AQI window
+    aqi_window: Duration,
+    include_caqi: bool,
+    version: Option<u8>, // Firmware version from the last frame read
+}
 
-    let i2c_bus = match I2cdev::new(&config.i2c_bus_device_path) {
-        Ok(bus) => bus,
-        Err(_) => return HttpResponse::InternalServerError().body("Failed to open I2C bus"),
-    };
+// Device details in /sensor_info
+#[derive(Serialize)]
+struct DeviceInfo {
+    firmware_version: Option<u8>,
+}
 
-    let mut pmsa003i = Pmsa003i::new(i2c_bus);
+impl Pmsa003iSensor {
+    // Add the newest reading and return the PM2.5 and PM10 averages over the window
+    fn rolling_average(&mut self, pm2_5: u16, pm10: u16) -> (f32, f32) {
+        let now = Instant::now();
+        self.history.push_back((now, pm2_5, pm10));
+        while let Some(&(taken_at, _, _)) = self.history.front() {
+            if now.duration_since(taken_at) <= self.aqi_window {
+                break;
+            }
+            self.history.pop_front();
+        }
 
-    match pmsa003i.read() {
-        Ok(data) => {
-            let pm1_0 = data.pm1;
-            let pm2_5 = data.pm2_5;
-            let pm10 = data.pm10;
+        let count = self.history.len() as f32;
+        let (sum_pm2_5, sum_pm10) = self
+            .history
+            .iter()
+            .fold((0.0, 0.0), |(a, b), &(_, pm2_5, pm10)| (a + pm2_5 as f32, b + pm10 as f32));
+        (sum_pm2_5 / count, sum_pm10 / count)
+    }
+}
 
-            let response = SensorData {
-                timestamp: Utc::now().to_rfc3339(),
-                model: "PMSA003I".to_string(),
-                pm1_0,
-                pm2_5,
-                pm10,
-            };
+impl Sensor for Pmsa003iSensor {
+    type Config = Config;
+    type Reading = SensorData;
 
-            HttpResponse::Ok().json(response)
-        }
-        Err(e) => {
-            eprintln!("Failed to read sensor data: {:?}", e);
-            HttpResponse::InternalServerError().body("Failed to read sensor data")
+    const MODEL: &'static str = "PMSA003I";
+
+    fn init(config: &Config) -> Result<Self, SensorError> {
+        let i2c_bus = I2cdev::new(&config.server.i2c_bus_device_path).map_err(SensorError::bus)?;
+
+        Ok(Pmsa003iSensor {
+            pmsa003i: PMSA003I::new(i2c_bus, config.i2c_address_decimal as u8),
+            history: VecDeque::new(),
+            aqi_window: Duration::from_secs(config.aqi_average_minutes * 60),
+            include_caqi: config.include_caqi,
+            version: None,
+        })
+    }
+
+    fn read(&mut self) -> Result<SensorData, SensorError> {
+        let data = self.pmsa003i.read()?;
+        self.version = Some(data.version);
+
+        // AQI is defined on ambient concentrations, so average the atmospheric values
+        let (pm2_5_average, pm10_average) = self.rolling_average(data.pm2_5_env, data.pm10_env);
+        let aqi_pm2_5 = aqi::epa_pm2_5(pm2_5_average);
+        let aqi_pm10 = aqi::epa_pm10(pm10_average);
+
+        Ok(SensorData {
+            pm1_0: data.pm1_0,
+            pm2_5: data.pm2_5,
+            pm10: data.pm10,
+            pm1_0_env: data.pm1_0_env,
+            pm2_5_env: data.pm2_5_env,
+            pm10_env: data.pm10_env,
+            particles_03um: data.particles_03um,
+            particles_05um: data.particles_05um,
+            particles_10um: data.particles_10um,
+            particles_25um: data.particles_25um,
+            particles_50um: data.particles_50um,
+            particles_100um: data.particles_100um,
+            pm2_5_average,
+            pm10_average,
+            aqi: aqi_pm2_5.max(aqi_pm10),
+            aqi_pm2_5,
+            aqi_pm10,
+            caqi: self.include_caqi.then(|| aqi::caqi(pm2_5_average, pm10_average)),
+        })
+    }
+
+    fn device_info(&mut self) -> Result<serde_json::Value, SensorError> {
+        let info = DeviceInfo {
+            firmware_version: self.version,
+        };
+        serde_json::to_value(info).map_err(SensorError::read)
+    }
+}
+
+// Sort driver errors into the shared error codes
+impl<E: embedded_hal::i2c::Error> From<pmsa003i::Error<E>> for SensorError {
+    fn from(e: pmsa003i::Error<E>) -> Self {
+        match e {
+            pmsa003i::Error::I2c(e) => SensorError::i2c(e),
+            pmsa003i::Error::BadFrame => SensorError::Read(String::from("Frame start characters missing")),
+            pmsa003i::Error::Checksum => SensorError::Crc(String::from("PMSA003I frame checksum")),
         }
     }
 }
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    env_logger::init_from_env(Env::default().default_filter_or("info"));
-
-    let config = read_or_create_config();
-
-    HttpServer::new(|| {
-        App::new()
-            .wrap(Logger::default())
-            .route("/sensor_data", web::get().to(get_sensor_data))
-    })
-    .bind((config.bind_address.as_str(), config.network_port))?
-    .run()
-    .await
-}
\ No newline at end of file
+fn main() -> std::io::Result<()> {
+    sensor_core::run::<Pmsa003iSensor>()
+}
@@ -0,0 +1,92 @@
+use embedded_hal::i2c::I2c;
+use std::fmt::Debug;
+
+const FRAME_LEN: usize = 32;
+const START_1: u8 = 0x42;
+const START_2: u8 = 0x4D;
+
+#[derive(Debug)]
+pub enum Error<E> {
+    I2c(E),
+    BadFrame, // Start characters missing, usually a misaligned read
+    Checksum, // Frame checksum did not match
+}
+
+impl<E> From<E> for Error<E> {
+    fn from(e: E) -> Self {
+        Error::I2c(e)
+    }
+}
+
+// Everything in a PMSA003I data frame, concentrations in µg/m³
+pub struct Reading {
+    // Standard particles (CF=1)
+    pub pm1_0: u16,
+    pub pm2_5: u16,
+    pub pm10: u16,
+    // Atmospheric environment
+    pub pm1_0_env: u16,
+    pub pm2_5_env: u16,
+    pub pm10_env: u16,
+    // Particles larger than the given size per 0.1 L of air
+    pub particles_03um: u16,
+    pub particles_05um: u16,
+    pub particles_10um: u16,
+    pub particles_25um: u16,
+    pub particles_50um: u16,
+    pub particles_100um: u16,
+    pub version: u8, // Firmware version
+}
+
+pub struct PMSA003I<I2C> {
+    i2c: I2C,
+    address: u8,
+}
+
+impl<I2C, E> PMSA003I<I2C>
+where
+    I2C: I2c<Error = E>,
+    E: Debug,
+{
+    pub fn new(i2c: I2C, address: u8) -> Self {
+        PMSA003I { i2c, address }
+    }
+
+    pub fn read(&mut self) -> Result<Reading, Error<E>> {
+        let mut frame = [0; FRAME_LEN];
+        self.i2c.read(self.address, &mut frame)?;
+        parse_frame(&frame)
+    }
+}
+
+fn parse_frame<E>(frame: &[u8; FRAME_LEN]) -> Result<Reading, Error<E>> {
+    if frame[0] != START_1 || frame[1] != START_2 {
+        return Err(Error::BadFrame);
+    }
+
+    // Checksum is the sum of every byte before it
+    let checksum: u16 = frame[..FRAME_LEN - 2].iter().map(|&b| b as u16).sum();
+    if checksum != word(frame, 30) {
+        return Err(Error::Checksum);
+    }
+
+    Ok(Reading {
+        pm1_0: word(frame, 4),
+        pm2_5: word(frame, 6),
+        pm10: word(frame, 8),
+        pm1_0_env: word(frame, 10),
+        pm2_5_env: word(frame, 12),
+        pm10_env: word(frame, 14),
+        particles_03um: word(frame, 16),
+        particles_05um: word(frame, 18),
+        particles_10um: word(frame, 20),
+        particles_25um: word(frame, 22),
+        particles_50um: word(frame, 24),
+        particles_100um: word(frame, 26),
+        version: frame[28],
+    })
+}
+
+fn word(frame: &[u8; FRAME_LEN], offset: usize) -> u16 {
+    u16::from_be_bytes([frame[offset], frame[offset + 1]])
+}
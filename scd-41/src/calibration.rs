@@ -0,0 +1,238 @@
+// Calibration and settings endpoints, all of them need the admin token from config.json
+
+use actix_web::{web, HttpResponse};
+use embedded_hal::i2c::I2c;
+use linux_embedded_hal::{Delay, I2cdev};
+use scd4x::Scd4x;
+use sensor_core::{Admin, Sampler, SensorError};
+use serde::{Deserialize, Serialize};
+use std::thread::sleep;
+use std::time::Duration;
+
+use crate::{scd4x_error, MeasurementMode, Scd41Sensor};
+
+// perform_forced_recalibration, sent directly because scd4x 0.3 rejects negative corrections
+const FRC_COMMAND: [u8; 2] = [0x36, 0x2F];
+
+// How long the sensor takes to answer perform_forced_recalibration
+const FRC_DELAY: Duration = Duration::from_millis(400);
+
+// Returned by the sensor when a forced recalibration could not be performed
+const FRC_FAILED: u16 = 0xFFFF;
+
+// The sensor reports the FRC correction offset by this much
+const FRC_OFFSET: i32 = 0x8000;
+
+// Current settings as stored in the sensor's RAM, persist writes them to EEPROM
+#[derive(Serialize)]
+struct Calibration {
+    serial_number: u64,
+    measurement_mode: MeasurementMode,
+    automatic_self_calibration: bool,
+    temperature_offset: f32, // °C subtracted from the measured temperature
+    altitude: u16,           // Metres above sea level used for pressure compensation
+}
+
+#[derive(Serialize)]
+struct ForcedRecalibrationResult {
+    correction_ppm: i32, // How far the sensor's CO2 readings were moved
+}
+
+#[derive(Deserialize)]
+struct ForcedRecalibration {
+    target_co2_ppm: u16, // CO2 concentration the sensor is currently exposed to
+}
+
+#[derive(Deserialize)]
+struct AutomaticSelfCalibration {
+    enabled: bool,
+}
+
+#[derive(Deserialize)]
+struct TemperatureOffset {
+    celsius: f32,
+}
+
+#[derive(Deserialize)]
+struct Altitude {
+    meters: u16,
+}
+
+pub fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/calibration")
+            .route("", web::get().to(get_calibration))
+            .route("/forced_recalibration", web::post().to(forced_recalibration))
+            .route("/automatic_self_calibration", web::put().to(set_automatic_self_calibration))
+            .route("/temperature_offset", web::put().to(set_temperature_offset))
+            .route("/altitude", web::put().to(set_altitude))
+            .route("/persist", web::post().to(persist)),
+    );
+}
+
+// Must be called while the sensor is idle
+fn read_calibration(sensor: &mut Scd4x<I2cdev, Delay>, mode: MeasurementMode) -> Result<Calibration, SensorError> {
+    Ok(Calibration {
+        serial_number: sensor.serial_number().map_err(scd4x_error)?,
+        measurement_mode: mode,
+        automatic_self_calibration: sensor.automatic_self_calibration().map_err(scd4x_error)?,
+        temperature_offset: sensor.temperature_offset().map_err(scd4x_error)?,
+        altitude: sensor.altitude().map_err(scd4x_error)?,
+    })
+}
+
+async fn get_calibration(
+    _admin: Admin,
+    sampler: web::Data<Sampler<Scd41Sensor>>,
+) -> Result<HttpResponse, SensorError> {
+    let calibration = sampler
+        .execute(|scd41| {
+            let mode = scd41.mode;
+            scd41.while_idle(|sensor| read_calibration(sensor, mode))
+        })
+        .await?;
+    Ok(HttpResponse::Ok().json(calibration))
+}
+
+// The sensor should have been running in periodic mode for at least 3 minutes in air with a
+// known, stable CO2 concentration before this is called
+async fn forced_recalibration(
+    _admin: Admin,
+    sampler: web::Data<Sampler<Scd41Sensor>>,
+    body: web::Json<ForcedRecalibration>,
+) -> Result<HttpResponse, SensorError> {
+    let target = body.target_co2_ppm;
+    let correction_ppm = sampler
+        .execute(move |scd41| {
+            let (i2c_bus_device_path, address) = (scd41.i2c_bus_device_path.clone(), scd41.address);
+            scd41.while_idle(|_| perform_forced_recalibration(&i2c_bus_device_path, address, target))
+        })
+        .await?;
+
+    Ok(HttpResponse::Ok().json(ForcedRecalibrationResult { correction_ppm }))
+}
+
+// Sensirion's CRC-8, polynomial 0x31 starting from 0xFF
+fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0xFF, |crc, byte| {
+        (0..8).fold(crc ^ byte, |crc, _| if crc & 0x80 != 0 { (crc << 1) ^ 0x31 } else { crc << 1 })
+    })
+}
+
+// Runs on a second handle to the bus while the sensor is idle, returns the correction in ppm
+fn perform_forced_recalibration(i2c_bus_device_path: &str, address: u8, target: u16) -> Result<i32, SensorError> {
+    let mut i2c = I2cdev::new(i2c_bus_device_path).map_err(SensorError::bus)?;
+
+    let target = target.to_be_bytes();
+    let command = [FRC_COMMAND[0], FRC_COMMAND[1], target[0], target[1], crc8(&target)];
+    i2c.write(address, &command).map_err(SensorError::i2c)?;
+    sleep(FRC_DELAY);
+
+    let mut response = [0; 3];
+    i2c.read(address, &mut response).map_err(SensorError::i2c)?;
+    decode_correction(response)
+}
+
+// The response is the correction plus 0x8000 and its CRC, or 0xFFFF if the sensor couldn't do it
+fn decode_correction(response: [u8; 3]) -> Result<i32, SensorError> {
+    if crc8(&response[..2]) != response[2] {
+        return Err(SensorError::Crc(String::from("SCD-41 forced recalibration response CRC")));
+    }
+
+    match u16::from_be_bytes([response[0], response[1]]) {
+        FRC_FAILED => Err(SensorError::Command(String::from("Forced recalibration failed"))),
+        correction => Ok(i32::from(correction) - FRC_OFFSET),
+    }
+}
+
+async fn set_automatic_self_calibration(
+    _admin: Admin,
+    sampler: web::Data<Sampler<Scd41Sensor>>,
+    body: web::Json<AutomaticSelfCalibration>,
+) -> Result<HttpResponse, SensorError> {
+    let enabled = body.enabled;
+    update(sampler, move |sensor| {
+        sensor.set_automatic_self_calibration(enabled).map_err(scd4x_error)
+    })
+    .await
+}
+
+async fn set_temperature_offset(
+    _admin: Admin,
+    sampler: web::Data<Sampler<Scd41Sensor>>,
+    body: web::Json<TemperatureOffset>,
+) -> Result<HttpResponse, SensorError> {
+    let celsius = body.celsius;
+    update(sampler, move |sensor| {
+        sensor.set_temperature_offset(celsius).map_err(scd4x_error)
+    })
+    .await
+}
+
+async fn set_altitude(
+    _admin: Admin,
+    sampler: web::Data<Sampler<Scd41Sensor>>,
+    body: web::Json<Altitude>,
+) -> Result<HttpResponse, SensorError> {
+    let meters = body.meters;
+    update(sampler, move |sensor| sensor.set_altitude(meters).map_err(scd4x_error)).await
+}
+
+// Settings otherwise revert on power cycle, the EEPROM is rated for about 2000 writes
+async fn persist(
+    _admin: Admin,
+    sampler: web::Data<Sampler<Scd41Sensor>>,
+) -> Result<HttpResponse, SensorError> {
+    update(sampler, |sensor| sensor.persist_settings().map_err(scd4x_error)).await
+}
+
+// Apply a setting and respond with the settings as the sensor now reports them
+async fn update<F>(sampler: web::Data<Sampler<Scd41Sensor>>, apply: F) -> Result<HttpResponse, SensorError>
+where
+    F: FnOnce(&mut Scd4x<I2cdev, Delay>) -> Result<(), SensorError> + Send + 'static,
+{
+    let calibration = sampler
+        .execute(|scd41| {
+            let mode = scd41.mode;
+            scd41.while_idle(|sensor| {
+                apply(sensor)?;
+                read_calibration(sensor, mode)
+            })
+        })
+        .await?;
+    Ok(HttpResponse::Ok().json(calibration))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(raw: u16) -> [u8; 3] {
+        let [high, low] = raw.to_be_bytes();
+        [high, low, crc8(&[high, low])]
+    }
+
+    #[test]
+    fn crc8_matches_the_datasheet_example() {
+        assert_eq!(crc8(&[0xBE, 0xEF]), 0x92);
+    }
+
+    #[test]
+    fn corrections_in_both_directions() {
+        assert_eq!(decode_correction(response(0x8000)).unwrap(), 0);
+        assert_eq!(decode_correction(response(0x8000 + 120)).unwrap(), 120);
+        assert_eq!(decode_correction(response(0x8000 - 120)).unwrap(), -120);
+    }
+
+    #[test]
+    fn failed_recalibration_is_an_error() {
+        assert!(matches!(decode_correction(response(FRC_FAILED)), Err(SensorError::Command(_))));
+    }
+
+    #[test]
+    fn corrupted_response_is_a_crc_error() {
+        let mut corrupted = response(0x8000);
+        corrupted[2] ^= 1;
+        assert!(matches!(decode_correction(corrupted), Err(SensorError::Crc(_))));
+    }
+}
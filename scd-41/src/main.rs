@@ -1,141 +1,235 @@
-use actix_web::{web, App, HttpServer, HttpResponse, middleware::Logger}; // Import necessary Actix Web components
+mod calibration;
+mod pressure;
+
 use scd4x::Scd4x; // Import SCD-41 sensor library
-use linux_embedded_hal::{I2cdev, Delay, I2CError};  // Import I2C device, delay, and I2CError from linux_embedded_hal
-use linux_embedded_hal::i2cdev::linux::LinuxI2CError; // Import LinuxI2CError from linux_embedded_hal
+use linux_embedded_hal::{I2cdev, Delay};  // Import I2C device and delay from linux_embedded_hal
+use actix_web::web; // Import web framework for the calibration routes
 use serde::{Deserialize, Serialize}; // Import serialization/deserialization from Serde
-use chrono::Utc; // Import Utc for timestamps
-use std::fs; // Import filesystem operations
-use std::fs::File; // Import file operations
-use std::io::Write; // Import write operations
-use env_logger::Env; // Import environment logger
-use std::fmt; // Import fmt for custom error formatting
+use pressure::{PressureCompensation, PressureFeed}; // Import ambient pressure compensation
+use sensor_core::{Psychrometrics, Sensor, SensorConfig, SensorError, ServerConfig}; // Import shared sensor server
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+// How often to ask the sensor whether a new measurement is ready
+const DATA_READY_POLL: Duration = Duration::from_millis(100);
+
+// Time the sensor needs after stop_periodic_measurement before it accepts other commands
+const STOP_DELAY: Duration = Duration::from_millis(500);
+
+// How the SCD-41 takes measurements, the slower modes heat the sensor less and use less power
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+enum MeasurementMode {
+    Periodic,         // New data every 5 seconds
+    LowPowerPeriodic, // New data every 30 seconds
+    SingleShot,       // Sensor idles until a reading is requested, each takes about 5 seconds
+}
+
+impl MeasurementMode {
+    // How often the sensor has new data, or how long a single shot takes
+    fn period(self) -> Duration {
+        match self {
+            MeasurementMode::Periodic | MeasurementMode::SingleShot => Duration::from_secs(5),
+            MeasurementMode::LowPowerPeriodic => Duration::from_secs(30),
+        }
+    }
+}
 
 // Configuration structure for the application
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 struct Config {
-    network_port: u16, // Port for the web server
+    #[serde(flatten)]
+    server: ServerConfig, // Port, bind address and I2C bus path
     i2c_address_decimal: u16, // I2C address of the SCD-41 sensor
-    i2c_bus_device_path: String, // Path to the I2C bus device
-    bind_address: String, // Address to bind the web server to
+    #[serde(default = "default_measurement_mode")]
+    measurement_mode: MeasurementMode, // periodic, low_power_periodic or single_shot
+    #[serde(default)]
+    pressure_compensation: Option<PressureCompensation>, // Where to get ambient pressure from, if anywhere
+    #[serde(default)]
+    include_psychrometrics: bool, // Add dew point, absolute humidity, VPD, heat index, humidex and wet bulb
+}
+
+fn default_measurement_mode() -> MeasurementMode {
+    MeasurementMode::Periodic
 }
 
 // Default implementation for the Config struct
 impl Default for Config {
     fn default() -> Self {
         Config {
-            network_port: 5002, // Default network port
+            server: ServerConfig::with_port(5002), // Default network port
             i2c_address_decimal: 0x62, // Default I2C address (98 in decimal)
-            i2c_bus_device_path: String::from("/dev/i2c-1"), // Default I2C bus device path
-            bind_address: String::from("0.0.0.0"), // Default bind address
+            measurement_mode: default_measurement_mode(),
+            pressure_compensation: None,
+            include_psychrometrics: false,
         }
     }
 }
 
-// Function to read the configuration from a file or create a default one if it doesn't exist
-fn read_or_create_config() -> Config {
-    let config_path = "config.json";
-    if let Ok(config_data) = fs::read_to_string(config_path) {
-        if let Ok(config) = serde_json::from_str(&config_data) {
-            return config;
-        }
+impl SensorConfig for Config {
+    fn server(&self) -> &ServerConfig {
+        &self.server
     }
 
-    let default_config = Config::default();
-    let config_data = serde_json::to_string_pretty(&default_config).unwrap();
-    let mut file = File::create(config_path).unwrap();
-    file.write_all(config_data.as_bytes()).unwrap();
-    default_config
+    fn i2c_address(&self) -> Option<u16> {
+        Some(self.i2c_address_decimal)
+    }
 }
 
 // Structure to hold sensor data
 #[derive(Serialize)]
 struct SensorData {
-    timestamp: String,
-    model: String,
     temperature: f32,
     humidity: f32,
     co2: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ambient_pressure: Option<u16>, // hPa the CO2 reading was compensated for
+    #[serde(flatten)]
+    psychrometrics: Option<Psychrometrics>, // Only with include_psychrometrics
 }
 
-// Define a custom error type
-#[derive(Debug)]
-enum SensorError {
-    I2cError(I2CError),
-    Scd4xError(scd4x::Error<I2CError>),
-    LinuxI2CError(LinuxI2CError),
+// Device details in /sensor_info
+#[derive(Serialize)]
+struct DeviceInfo {
+    serial_number: u64,
+    measurement_mode: MeasurementMode,
 }
 
-impl fmt::Display for SensorError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            SensorError::I2cError(e) => write!(f, "I2C error: {}", e),
-            SensorError::Scd4xError(e) => write!(f, "SCD4x error: {:?}", e),
-            SensorError::LinuxI2CError(e) => write!(f, "Linux I2C error: {:?}", e),
-        }
+struct Scd41Sensor {
+    sensor: Scd4x<I2cdev, Delay>,
+    i2c_bus_device_path: String, // For commands scd4x doesn't handle, sent on a second handle to the bus
+    address: u8,
+    mode: MeasurementMode,
+    serial_number: u64,
+    pressure: Option<PressureFeed>,
+    include_psychrometrics: bool,
+}
+
+// Sort driver errors into the shared error codes, anything else the sensor rejected is a failed command
+fn scd4x_error<E: embedded_hal::i2c::Error>(e: scd4x::Error<E>) -> SensorError {
+    match e {
+        scd4x::Error::I2c(e) => SensorError::i2c(e),
+        scd4x::Error::Crc => SensorError::Crc(String::from("SCD-41 response CRC")),
+        e => SensorError::command(e),
     }
 }
 
-impl std::error::Error for SensorError {}
+impl Scd41Sensor {
+    // Periodic modes run on their own once started, single shot waits for each read
+    fn start_measurement(&mut self) -> Result<(), SensorError> {
+        match self.mode {
+            MeasurementMode::Periodic => self.sensor.start_periodic_measurement(),
+            MeasurementMode::LowPowerPeriodic => self.sensor.start_low_power_periodic_measurements(),
+            MeasurementMode::SingleShot => Ok(()),
+        }
+        .map_err(scd4x_error)
+    }
+
+    // Settings and calibration commands are only accepted while the sensor is idle, so stop
+    // measuring, run `f` and start again afterwards even if `f` failed
+    fn while_idle<T>(
+        &mut self,
+        f: impl FnOnce(&mut Scd4x<I2cdev, Delay>) -> Result<T, SensorError>,
+    ) -> Result<T, SensorError> {
+        if self.mode != MeasurementMode::SingleShot {
+            self.sensor.stop_periodic_measurement().map_err(scd4x_error)?;
+            sleep(STOP_DELAY);
+        }
 
-impl From<I2CError> for SensorError {
-    fn from(err: I2CError) -> SensorError {
-        SensorError::I2cError(err)
+        let result = f(&mut self.sensor);
+        self.start_measurement()?;
+        result
     }
-}
 
-impl From<scd4x::Error<I2CError>> for SensorError {
-    fn from(err: scd4x::Error<I2CError>) -> SensorError {
-        SensorError::Scd4xError(err)
+    // Poll the data ready status rather than sleeping for a fixed time
+    fn wait_for_data(&mut self) -> Result<(), SensorError> {
+        let deadline = Instant::now() + self.mode.period() * 2;
+        while !self.sensor.data_ready_status().map_err(scd4x_error)? {
+            if Instant::now() > deadline {
+                return Err(SensorError::Timeout(String::from("No measurement from the SCD-41")));
+            }
+            sleep(DATA_READY_POLL);
+        }
+        Ok(())
     }
 }
 
-impl From<LinuxI2CError> for SensorError {
-    fn from(err: LinuxI2CError) -> SensorError {
-        SensorError::LinuxI2CError(err)
+impl Sensor for Scd41Sensor {
+    type Config = Config;
+    type Reading = SensorData;
+
+    const MODEL: &'static str = "SCD-41";
+
+    fn init(config: &Config) -> Result<Self, SensorError> {
+        let i2c_bus = I2cdev::new(&config.server.i2c_bus_device_path).map_err(SensorError::bus)?;
+        let mut sensor = Scd4x::new(i2c_bus, Delay);
+
+        // Stop any ongoing measurement, the sensor ignores other commands while measuring
+        sensor.stop_periodic_measurement().map_err(scd4x_error)?;
+        sleep(STOP_DELAY); // Wait for the sensor to stop
+
+        // Only readable while idle, so read it once now for /sensor_info
+        let serial_number = sensor.serial_number().map_err(scd4x_error)?;
+
+        let mut scd41 = Scd41Sensor {
+            sensor,
+            i2c_bus_device_path: config.server.i2c_bus_device_path.clone(),
+            address: config.i2c_address_decimal as u8,
+            mode: config.measurement_mode,
+            serial_number,
+            pressure: config
+                .pressure_compensation
+                .as_ref()
+                .map(|compensation| PressureFeed::new(compensation, &config.server.i2c_bus_device_path)),
+            include_psychrometrics: config.include_psychrometrics,
+        };
+        scd41.start_measurement()?;
+        Ok(scd41)
     }
-}
 
-// Function to read data from the SCD-41 sensor
-fn read_sensor_data(config: &Config) -> Result<SensorData, SensorError> {
-    let i2c_bus = I2cdev::new(&config.i2c_bus_device_path)?;
-    let mut sensor = Scd4x::new(i2c_bus, Delay);
-    
-    // Stop any ongoing measurement
-    sensor.stop_periodic_measurement()?;
-    std::thread::sleep(std::time::Duration::from_secs(1)); // Wait for the sensor to stop
-
-    // Start a new periodic measurement
-    sensor.start_periodic_measurement()?;
-    std::thread::sleep(std::time::Duration::from_secs(5)); // Wait for the first measurement
-
-    let data = sensor.measurement()?;
-    Ok(SensorData {
-        timestamp: Utc::now().to_rfc3339(),
-        model: String::from("SCD-41"),
-        temperature: data.temperature,
-        humidity: data.humidity,
-        co2: data.co2 as f32, // Convert u16 to f32
-    })
+    // Function to read the next measurement from the SCD-41 sensor
+    fn read(&mut self) -> Result<SensorData, SensorError> {
+        // Can be sent while measuring, it applies from the next measurement
+        if let Some(pressure) = self.pressure.as_mut() {
+            pressure.update(&mut self.sensor);
+        }
+
+        if self.mode == MeasurementMode::SingleShot {
+            self.sensor.measure_single_shot_non_blocking().map_err(scd4x_error)?;
+        }
+        self.wait_for_data()?;
+
+        let data = self.sensor.measurement().map_err(scd4x_error)?;
+        Ok(SensorData {
+            temperature: data.temperature,
+            humidity: data.humidity,
+            co2: data.co2 as f32, // Convert u16 to f32
+            ambient_pressure: self.pressure.as_ref().and_then(PressureFeed::current),
+            psychrometrics: self
+                .include_psychrometrics
+                .then(|| Psychrometrics::new(data.temperature, data.humidity)),
+        })
+    }
+
+    // Reading faster than the sensor measures would only return the same values
+    fn min_sample_interval(config: &Config) -> Duration {
+        config.measurement_mode.period()
+    }
+
+    fn device_info(&mut self) -> Result<serde_json::Value, SensorError> {
+        let info = DeviceInfo {
+            serial_number: self.serial_number,
+            measurement_mode: self.mode,
+        };
+        serde_json::to_value(info).map_err(SensorError::read)
+    }
+
+    fn routes(cfg: &mut web::ServiceConfig) {
+        calibration::routes(cfg);
+    }
 }
 
 // Main function to start the web server
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    env_logger::init_from_env(Env::default().default_filter_or("info"));
-    let config = read_or_create_config();
-
-    HttpServer::new(move || {
-        App::new()
-            .wrap(Logger::default())
-            .route("/sensor_data", web::get().to(|| async {
-                let config = read_or_create_config();
-                match read_sensor_data(&config) {
-                    Ok(sensor_data) => HttpResponse::Ok().json(sensor_data),
-                    Err(e) => HttpResponse::InternalServerError().body(format!("Error reading sensor data: {}", e)),
-                }
-            }))
-    })
-    .bind((config.bind_address.as_str(), config.network_port))?
-    .run()
-    .await
-}
\ No newline at end of file
+fn main() -> std::io::Result<()> {
+    sensor_core::run::<Scd41Sensor>()
+}
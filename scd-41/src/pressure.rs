@@ -0,0 +1,154 @@
+// Ambient pressure for the SCD-41's CO2 compensation, taken from another sensor
+
+use bme280_api::bme280::BME280;
+use linux_embedded_hal::{Delay, I2cdev};
+use log::{info, warn};
+use scd4x::Scd4x;
+use sensor_core::SensorError;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+// Range the SCD-41 accepts for set_ambient_pressure, in hPa
+const MIN_PRESSURE: f32 = 700.0;
+const MAX_PRESSURE: f32 = 1200.0;
+
+// Don't let a slow sensor-api hold up CO2 readings for long
+const URL_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "source", rename_all = "snake_case")]
+pub enum PressureSource {
+    Url { url: String }, // A sensor-api /sensor_data endpoint that reports "pressure" in hPa
+    Bme280 { i2c_address_decimal: u16 }, // A BME280 on the same I2C bus, its settings are left alone
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PressureCompensation {
+    #[serde(flatten)]
+    pub source: PressureSource,
+    #[serde(default = "default_update_interval_s")]
+    pub update_interval_s: u64, // How often the pressure is fetched and sent to the SCD-41
+}
+
+fn default_update_interval_s() -> u64 {
+    60
+}
+
+#[derive(Deserialize)]
+struct RemoteReading {
+    pressure: f32,
+}
+
+// An opened pressure source
+enum Reader {
+    Url { agent: ureq::Agent, url: String },
+    Bme280 { bme280: BME280<I2cdev> },
+}
+
+impl Reader {
+    fn open(source: &PressureSource, i2c_bus_device_path: &str) -> Result<Self, SensorError> {
+        match source {
+            PressureSource::Url { url } => Ok(Reader::Url {
+                agent: ureq::AgentBuilder::new().timeout(URL_TIMEOUT).build(),
+                url: url.clone(),
+            }),
+            PressureSource::Bme280 { i2c_address_decimal } => {
+                // A second handle on the bus, the kernel serialises transfers between them
+                let i2c_bus = I2cdev::new(i2c_bus_device_path).map_err(SensorError::bus)?;
+                let mut bme280 = BME280::new(i2c_bus, *i2c_address_decimal as u8);
+                // The BME280 service may own this chip, so it isn't reset or reconfigured
+                bme280.attach()?;
+                Ok(Reader::Bme280 { bme280 })
+            }
+        }
+    }
+
+    // Current pressure in hPa
+    fn read(&mut self) -> Result<f32, SensorError> {
+        match self {
+            Reader::Url { agent, url } => {
+                let reading: RemoteReading = agent
+                    .get(url)
+                    .call()
+                    .map_err(SensorError::read)?
+                    .into_json()
+                    .map_err(SensorError::read)?;
+                Ok(reading.pressure)
+            }
+            Reader::Bme280 { bme280 } => {
+                let data = bme280.measure_shared()?;
+                Ok(data.pressure / 100.0) // Convert pressure from Pa to hPa
+            }
+        }
+    }
+}
+
+// Keeps the SCD-41's ambient pressure up to date from the configured source
+pub struct PressureFeed {
+    source: PressureSource,
+    i2c_bus_device_path: String,
+    interval: Duration,
+    reader: Option<Reader>,
+    next_update: Instant,
+    current: Option<u16>, // Pressure last sent to the sensor
+}
+
+impl PressureFeed {
+    pub fn new(compensation: &PressureCompensation, i2c_bus_device_path: &str) -> Self {
+        PressureFeed {
+            source: compensation.source.clone(),
+            i2c_bus_device_path: i2c_bus_device_path.to_string(),
+            interval: Duration::from_secs(compensation.update_interval_s.max(1)),
+            reader: None,
+            next_update: Instant::now(),
+            current: None,
+        }
+    }
+
+    // Pressure in hPa the sensor is compensating for, None until one has been sent
+    pub fn current(&self) -> Option<u16> {
+        self.current
+    }
+
+    // Send a fresh pressure to the sensor when one is due. A failing source is only logged,
+    // the sensor keeps compensating with the last value it was given
+    pub fn update(&mut self, sensor: &mut Scd4x<I2cdev, Delay>) {
+        let now = Instant::now();
+        if now < self.next_update {
+            return;
+        }
+        self.next_update = now + self.interval;
+
+        match self.fetch() {
+            Ok(pressure) => {
+                let pressure = pressure.round() as u16;
+                match sensor.set_ambient_pressure(pressure) {
+                    Ok(()) => {
+                        if self.current.is_none() {
+                            info!("Compensating CO2 for ambient pressure, starting at {} hPa", pressure);
+                        }
+                        self.current = Some(pressure);
+                    }
+                    Err(e) => warn!("Failed to set ambient pressure: {:?}", e),
+                }
+            }
+            Err(e) => {
+                warn!("Failed to get ambient pressure: {}", e);
+                self.reader = None; // Open the source again next time
+            }
+        }
+    }
+
+    fn fetch(&mut self) -> Result<f32, SensorError> {
+        let reader = match self.reader.take() {
+            Some(reader) => reader,
+            None => Reader::open(&self.source, &self.i2c_bus_device_path)?,
+        };
+        let pressure = self.reader.insert(reader).read()?;
+
+        if !(MIN_PRESSURE..=MAX_PRESSURE).contains(&pressure) {
+            return Err(SensorError::Read(format!("{} hPa is outside the range the SCD-41 accepts", pressure)));
+        }
+        Ok(pressure)
+    }
+}
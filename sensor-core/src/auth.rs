@@ -0,0 +1,54 @@
+use actix_web::dev::Payload;
+use actix_web::error::InternalError;
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::http::StatusCode;
+use actix_web::{web, FromRequest, HttpRequest};
+use std::future::{ready, Ready};
+
+use crate::config::ServerConfig;
+use crate::error::error_response;
+
+// Extractor for endpoints that change the sensor, requires `Authorization: Bearer <admin_token>`
+pub struct Admin;
+
+impl FromRequest for Admin {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(authorize(req))
+    }
+}
+
+fn authorize(req: &HttpRequest) -> Result<Admin, actix_web::Error> {
+    let token = req
+        .app_data::<web::Data<ServerConfig>>()
+        .and_then(|server| server.admin_token.clone());
+
+    // Without a token configured the admin endpoints are switched off
+    let token = match token {
+        Some(token) if !token.is_empty() => token,
+        _ => {
+            return Err(rejected(
+                StatusCode::FORBIDDEN,
+                "forbidden",
+                "Admin endpoints are disabled, set admin_token in config.json",
+            ))
+        }
+    };
+
+    let provided = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    match provided {
+        Some(provided) if provided == token => Ok(Admin),
+        _ => Err(rejected(StatusCode::UNAUTHORIZED, "unauthorized", "Missing or invalid admin token")),
+    }
+}
+
+fn rejected(status: StatusCode, code: &str, message: &'static str) -> actix_web::Error {
+    InternalError::from_response(message, error_response(status, code, message)).into()
+}
@@ -0,0 +1,103 @@
+use log::{error, warn};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+pub const CONFIG_PATH: &str = "config.json";
+
+// Settings every sensor service has in its config.json
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ServerConfig {
+    pub network_port: u16, // Port for the web server
+    pub i2c_bus_device_path: String, // Path to the I2C bus device
+    pub bind_address: String, // Address to bind the web server to
+    #[serde(default = "default_sample_interval_ms")]
+    pub sample_interval_ms: u64, // How often the background sampler reads the sensor
+    #[serde(default = "default_history_window_s")]
+    pub history_window_s: u64, // How far back /history goes, 0 disables it
+    #[serde(default)]
+    pub admin_token: Option<String>, // Bearer token for endpoints that change the sensor, unset disables them
+    #[serde(default)]
+    pub mqtt: Option<MqttConfig>, // Publish readings to an MQTT broker, unset disables it
+}
+
+// Broker connection and topics for the MQTT output
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MqttConfig {
+    pub host: String,
+    #[serde(default = "default_mqtt_port")]
+    pub port: u16,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default)]
+    pub node_id: Option<String>, // Identifies this sensor in topics and to Home Assistant, defaults to <model>_<network_port>
+    #[serde(default)]
+    pub topic: Option<String>, // Base topic, defaults to sensor-api/<node_id>
+    #[serde(default = "default_discovery_prefix")]
+    pub discovery_prefix: Option<String>, // Home Assistant discovery prefix, null disables discovery
+}
+
+fn default_mqtt_port() -> u16 {
+    1883
+}
+
+fn default_discovery_prefix() -> Option<String> {
+    Some(String::from("homeassistant"))
+}
+
+fn default_sample_interval_ms() -> u64 {
+    5000
+}
+
+fn default_history_window_s() -> u64 {
+    24 * 60 * 60
+}
+
+impl ServerConfig {
+    // Defaults for everything but the port, which is unique per sensor
+    pub fn with_port(network_port: u16) -> Self {
+        ServerConfig {
+            network_port,
+            i2c_bus_device_path: String::from("/dev/i2c-1"),
+            bind_address: String::from("0.0.0.0"),
+            sample_interval_ms: default_sample_interval_ms(),
+            history_window_s: default_history_window_s(),
+            admin_token: None,
+            mqtt: None,
+        }
+    }
+}
+
+// Implemented by each sensor's Config, which embeds a ServerConfig with #[serde(flatten)]
+pub trait SensorConfig: Serialize + DeserializeOwned + Default + Clone + Send + Sync + 'static {
+    fn server(&self) -> &ServerConfig;
+
+    // Reported by /sensor_info
+    fn i2c_address(&self) -> Option<u16> {
+        None
+    }
+}
+
+// Read the configuration from config.json or create a default one if it doesn't exist
+pub fn read_or_create_config<C: Serialize + DeserializeOwned + Default>() -> C {
+    match fs::read_to_string(CONFIG_PATH) {
+        Ok(config_data) => match serde_json::from_str(&config_data) {
+            Ok(config) => config,
+            Err(e) => {
+                // Keep the user's file so it can be fixed, but carry on with defaults
+                error!("Failed to parse {}: {}, using defaults", CONFIG_PATH, e);
+                C::default()
+            }
+        },
+        Err(_) => {
+            let default_config = C::default();
+            let config_data = serde_json::to_string_pretty(&default_config).unwrap();
+            if let Err(e) = fs::write(CONFIG_PATH, config_data) {
+                warn!("Failed to write default {}: {}", CONFIG_PATH, e);
+            }
+            default_config
+        }
+    }
+}
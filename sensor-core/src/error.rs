@@ -0,0 +1,126 @@
+use actix_web::http::StatusCode;
+use actix_web::HttpResponse;
+use chrono::Utc;
+use embedded_hal::i2c::{self, ErrorKind};
+use serde::Serialize;
+use std::fmt;
+use std::sync::OnceLock;
+
+// Model reported in error bodies, each process serves a single sensor
+static SENSOR_MODEL: OnceLock<&'static str> = OnceLock::new();
+
+pub(crate) fn set_sensor_model(model: &'static str) {
+    let _ = SENSOR_MODEL.set(model);
+}
+
+// Errors a sensor can report while being set up or read
+#[derive(Debug, Clone)]
+pub enum SensorError {
+    Bus(String),      // The I2C bus could not be opened or a transfer failed
+    NotFound(String), // Nothing acknowledged at the address, or the chip ID was wrong
+    Init(String),     // The sensor did not initialise
+    Crc(String),      // Data arrived but its checksum did not match
+    Timeout(String),  // The sensor didn't finish a measurement in time
+    NotReady(String), // No data available yet
+    Read(String),     // Reading data from the sensor failed
+    Command(String),  // A command sent to the sensor, such as a calibration, failed
+}
+
+impl SensorError {
+    // Helpers so drivers can write `.map_err(SensorError::read)?`
+    pub fn bus<E: fmt::Debug>(e: E) -> Self {
+        SensorError::Bus(format!("{:?}", e))
+    }
+
+    pub fn init<E: fmt::Debug>(e: E) -> Self {
+        SensorError::Init(format!("{:?}", e))
+    }
+
+    pub fn read<E: fmt::Debug>(e: E) -> Self {
+        SensorError::Read(format!("{:?}", e))
+    }
+
+    pub fn command<E: fmt::Debug>(e: E) -> Self {
+        SensorError::Command(format!("{:?}", e))
+    }
+
+    // A failed I2C transfer, no acknowledge means nothing answered at the sensor's address
+    pub fn i2c<E: i2c::Error>(e: E) -> Self {
+        match e.kind() {
+            ErrorKind::NoAcknowledge(_) => SensorError::NotFound(format!("{:?}", e)),
+            _ => SensorError::Bus(format!("{:?}", e)),
+        }
+    }
+
+    // Stable identifier for clients to match on
+    pub fn code(&self) -> &'static str {
+        match self {
+            SensorError::Bus(_) => "bus_error",
+            SensorError::NotFound(_) => "device_not_found",
+            SensorError::Init(_) => "init_failed",
+            SensorError::Crc(_) => "crc_error",
+            SensorError::Timeout(_) => "timeout",
+            SensorError::NotReady(_) => "not_ready",
+            SensorError::Read(_) => "read_failed",
+            SensorError::Command(_) => "command_failed",
+        }
+    }
+}
+
+impl fmt::Display for SensorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SensorError::Bus(e) => write!(f, "I2C bus error: {}", e),
+            SensorError::NotFound(e) => write!(f, "Sensor not found: {}", e),
+            SensorError::Init(e) => write!(f, "Failed to initialize sensor: {}", e),
+            SensorError::Crc(e) => write!(f, "Checksum mismatch: {}", e),
+            SensorError::Timeout(e) => write!(f, "Timed out: {}", e),
+            SensorError::NotReady(e) => write!(f, "Sensor not ready: {}", e),
+            SensorError::Read(e) => write!(f, "Failed to read sensor data: {}", e),
+            SensorError::Command(e) => write!(f, "Sensor command failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SensorError {}
+
+// Lets handlers return SensorError directly
+impl actix_web::ResponseError for SensorError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            SensorError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            SensorError::Command(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            _ => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        error_response(self.status_code(), self.code(), &self.to_string())
+    }
+}
+
+// Body of every error response, { "error": { ... } }
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    error: ErrorDetail<'a>,
+}
+
+#[derive(Serialize)]
+struct ErrorDetail<'a> {
+    code: &'a str,
+    message: &'a str,
+    sensor: &'static str,
+    timestamp: String,
+}
+
+// Build an error response in the shared format, for errors that aren't a SensorError
+pub fn error_response(status: StatusCode, code: &str, message: &str) -> HttpResponse {
+    HttpResponse::build(status).json(ErrorBody {
+        error: ErrorDetail {
+            code,
+            message,
+            sensor: SENSOR_MODEL.get().copied().unwrap_or("unknown"),
+            timestamp: Utc::now().to_rfc3339(),
+        },
+    })
+}
@@ -0,0 +1,104 @@
+use actix_web::{web, HttpResponse};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::config::SensorConfig;
+use crate::error::SensorError;
+use crate::sampler::{Counters, Sampler};
+use crate::sensor::Sensor;
+
+// What /sensor_info reports about the service itself, worked out once at startup
+pub(crate) struct ServiceInfo {
+    pub(crate) i2c_bus: String,
+    pub(crate) i2c_address: Option<u16>,
+    config: Value, // config.json as loaded, with secrets hidden
+}
+
+impl ServiceInfo {
+    pub(crate) fn new<C: SensorConfig>(config: &C) -> Self {
+        let mut config_json = serde_json::to_value(config).unwrap_or(Value::Null);
+        redact(config_json.get_mut("admin_token"));
+        redact(config_json.get_mut("mqtt").and_then(|mqtt| mqtt.get_mut("password")));
+
+        ServiceInfo {
+            i2c_bus: config.server().i2c_bus_device_path.clone(),
+            i2c_address: config.i2c_address(),
+            config: config_json,
+        }
+    }
+}
+
+fn redact(secret: Option<&mut Value>) {
+    if let Some(secret) = secret.filter(|secret| !secret.is_null()) {
+        *secret = Value::from("<redacted>");
+    }
+}
+
+#[derive(Serialize)]
+struct Status {
+    status: &'static str,
+}
+
+// Structure returned by /sensor_info
+#[derive(Serialize)]
+struct SensorInfo<'a> {
+    model: &'static str,
+    i2c_bus: &'a str,
+    i2c_address: Option<u16>,
+    uptime_s: u64,
+    initialised: bool,
+    last_reading: Option<String>, // Timestamp of the last successful read
+    last_error: Option<String>,
+    counters: Counters,
+    device: Value, // Sensor specific details, null when the sensor can't be reached
+    config: &'a Value,
+}
+
+pub(crate) fn routes<S: Sensor>(cfg: &mut web::ServiceConfig) {
+    cfg.route("/healthz", web::get().to(healthz))
+        .route("/readyz", web::get().to(readyz::<S>))
+        .route("/sensor_info", web::get().to(sensor_info::<S>));
+}
+
+// The process is up and serving requests, whatever state the sensor is in
+async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().json(Status { status: "ok" })
+}
+
+// The sensor is initialised and has been read recently
+async fn readyz<S: Sensor>(sampler: web::Data<Sampler<S>>) -> Result<HttpResponse, SensorError> {
+    let state = sampler.state();
+    let fresh = state
+        .latest
+        .as_ref()
+        .is_some_and(|sample| sample.age() <= sampler.stale_after());
+
+    if state.initialised && fresh {
+        return Ok(HttpResponse::Ok().json(Status { status: "ready" }));
+    }
+
+    Err(match &state.last_error {
+        Some(e) => e.clone(),
+        None if !state.initialised => SensorError::NotReady(String::from("Sensor is not initialised")),
+        None => SensorError::NotReady(String::from("No recent reading")),
+    })
+}
+
+async fn sensor_info<S: Sensor>(sampler: web::Data<Sampler<S>>, info: web::Data<ServiceInfo>) -> HttpResponse {
+    // Fails when the sensor isn't initialised, the rest is still worth reporting
+    let device = sampler.execute(|sensor| sensor.device_info()).await.unwrap_or(Value::Null);
+
+    let state = sampler.state();
+    HttpResponse::Ok().json(SensorInfo {
+        model: S::MODEL,
+        i2c_bus: &info.i2c_bus,
+        i2c_address: info.i2c_address,
+        uptime_s: sampler.uptime().as_secs(),
+        initialised: state.initialised,
+        last_reading: state.latest.as_ref().map(|sample| sample.timestamp.to_rfc3339()),
+        last_error: state.last_error.as_ref().map(|e| e.to_string()),
+        counters: state.counters,
+        device,
+        config: &info.config,
+    })
+}
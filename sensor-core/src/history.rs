@@ -0,0 +1,399 @@
+// Past readings kept in memory for /history, so trends can be shown without InfluxDB
+
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse};
+use chrono::{DateTime, Duration, DurationRound, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, VecDeque};
+
+use crate::error::error_response;
+use crate::sampler::Sampler;
+use crate::sensor::Sensor;
+
+// Upper limit whatever the window and sample interval, about 10 MB for a sensor with 10 fields
+const MAX_ENTRIES: usize = 100_000;
+
+// Bucket size when the query doesn't give a step
+const DEFAULT_STEP_S: i64 = 300;
+
+// Keeps responses to a sensible size, ask for a bigger step instead
+const MAX_BUCKETS: i64 = 10_000;
+
+// Numeric fields of a reading, flags as 1 or 0, the rest is skipped
+pub(crate) fn numeric_fields<R: Serialize>(reading: &R) -> Vec<(String, f64)> {
+    match serde_json::to_value(reading) {
+        Ok(Value::Object(fields)) => fields
+            .into_iter()
+            .filter_map(|(field, value)| match value {
+                Value::Number(n) => n.as_f64().map(|n| (field, n)),
+                Value::Bool(b) => Some((field, if b { 1.0 } else { 0.0 })),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+struct Entry {
+    timestamp: DateTime<Utc>,
+    values: Vec<Option<f64>>, // Indexed like History::fields, shorter if fields were added later
+}
+
+// Ring buffer of readings, the oldest are dropped once they fall out of the window
+pub(crate) struct History {
+    window: Duration,
+    fields: Vec<String>,
+    entries: VecDeque<Entry>,
+}
+
+impl History {
+    pub(crate) fn new(window_s: u64) -> Self {
+        History {
+            window: Duration::seconds(window_s as i64),
+            fields: Vec::new(),
+            entries: VecDeque::new(),
+        }
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        !self.window.is_zero()
+    }
+
+    pub(crate) fn record(&mut self, timestamp: DateTime<Utc>, fields: Vec<(String, f64)>) {
+        let mut values = vec![None; self.fields.len()];
+        for (field, value) in fields {
+            let index = match self.fields.iter().position(|known| *known == field) {
+                Some(index) => index,
+                None => {
+                    self.fields.push(field);
+                    values.push(None);
+                    self.fields.len() - 1
+                }
+            };
+            values[index] = Some(value);
+        }
+        self.entries.push_back(Entry { timestamp, values });
+
+        let oldest = timestamp - self.window;
+        while self
+            .entries
+            .front()
+            .is_some_and(|entry| entry.timestamp < oldest || self.entries.len() > MAX_ENTRIES)
+        {
+            self.entries.pop_front();
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct HistoryQuery {
+    since: Option<String>, // RFC 3339 timestamp, or how long ago such as 6h
+    until: Option<String>,
+    step: Option<String>, // Bucket size such as 90s, 5m, 1h or 1d
+}
+
+// Min, max and mean of one field over one bucket
+#[derive(Serialize)]
+struct Point {
+    timestamp: String, // Start of the bucket
+    min: f64,
+    max: f64,
+    mean: f64,
+    count: u32,
+}
+
+struct Bucket {
+    start: DateTime<Utc>,
+    min: f64,
+    max: f64,
+    sum: f64,
+    count: u32,
+}
+
+impl From<Bucket> for Point {
+    fn from(bucket: Bucket) -> Self {
+        Point {
+            timestamp: bucket.start.to_rfc3339(),
+            min: bucket.min,
+            max: bucket.max,
+            mean: bucket.sum / bucket.count as f64,
+            count: bucket.count,
+        }
+    }
+}
+
+// Structure returned by /history, buckets without readings are left out
+#[derive(Serialize)]
+struct HistoryResponse {
+    model: &'static str,
+    since: String,
+    until: String,
+    step_s: i64,
+    series: BTreeMap<String, Vec<Point>>,
+}
+
+// A number followed by s, m, h or d, a bare number is seconds
+fn parse_duration(value: &str) -> Option<Duration> {
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(split) => value.split_at(split),
+        None => (value, "s"),
+    };
+    let number: i64 = number.parse().ok()?;
+    match unit {
+        "s" => Duration::try_seconds(number),
+        "m" => Duration::try_minutes(number),
+        "h" => Duration::try_hours(number),
+        "d" => Duration::try_days(number),
+        _ => None,
+    }
+}
+
+fn parse_time(value: &str, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    match DateTime::parse_from_rfc3339(value) {
+        Ok(time) => Some(time.with_timezone(&Utc)),
+        Err(_) => parse_duration(value).and_then(|ago| now.checked_sub_signed(ago)),
+    }
+}
+
+fn bad_request(message: &str) -> HttpResponse {
+    error_response(StatusCode::BAD_REQUEST, "bad_request", message)
+}
+
+pub(crate) fn routes<S: Sensor>(cfg: &mut web::ServiceConfig) {
+    cfg.route("/history", web::get().to(get_history::<S>));
+}
+
+async fn get_history<S: Sensor>(sampler: web::Data<Sampler<S>>, query: web::Query<HistoryQuery>) -> HttpResponse {
+    let history = sampler.history();
+    if !history.is_enabled() {
+        return bad_request("History is disabled, set history_window_s in config.json");
+    }
+
+    let (since, until, step) = match range(&query, Utc::now(), history.window) {
+        Ok(range) => range,
+        Err(message) => return bad_request(message),
+    };
+
+    HttpResponse::Ok().json(HistoryResponse {
+        model: S::MODEL,
+        since: since.to_rfc3339(),
+        until: until.to_rfc3339(),
+        step_s: step.num_seconds(),
+        series: series(&history, since, until, step),
+    })
+}
+
+// since, until and step from the query, defaulting to the whole window in 5 minute buckets
+fn range(
+    query: &HistoryQuery,
+    now: DateTime<Utc>,
+    window: Duration,
+) -> Result<(DateTime<Utc>, DateTime<Utc>, Duration), &'static str> {
+    let since = match query.since.as_deref().map(|since| parse_time(since, now)) {
+        Some(Some(since)) => since,
+        Some(None) => return Err("since must be an RFC 3339 timestamp or a duration such as 6h"),
+        None => now - window,
+    };
+    let until = match query.until.as_deref().map(|until| parse_time(until, now)) {
+        Some(Some(until)) => until,
+        Some(None) => return Err("until must be an RFC 3339 timestamp or a duration such as 1h"),
+        None => now,
+    };
+    let step = match query.step.as_deref().map(parse_duration) {
+        Some(Some(step)) if step >= Duration::seconds(1) => step,
+        Some(_) => return Err("step must be a duration of at least 1s, such as 5m"),
+        None => Duration::seconds(DEFAULT_STEP_S),
+    };
+
+    if since > until {
+        return Err("since must be before until");
+    }
+    if (until - since).num_seconds() / step.num_seconds() > MAX_BUCKETS {
+        return Err("Too many buckets, use a larger step or a shorter range");
+    }
+    Ok((since, until, step))
+}
+
+// Readings between since and until summarised per field and bucket
+fn series(
+    history: &History,
+    since: DateTime<Utc>,
+    until: DateTime<Utc>,
+    step: Duration,
+) -> BTreeMap<String, Vec<Point>> {
+    let mut buckets: Vec<Vec<Bucket>> = history.fields.iter().map(|_| Vec::new()).collect();
+    let entries = history
+        .entries
+        .iter()
+        .skip_while(|entry| entry.timestamp < since)
+        .take_while(|entry| entry.timestamp <= until);
+
+    for entry in entries {
+        // Buckets line up with the clock, so charts don't shift between queries
+        let start = entry.timestamp.duration_trunc(step).unwrap_or(entry.timestamp);
+
+        for (field, value) in entry.values.iter().enumerate() {
+            let Some(value) = *value else { continue };
+            match buckets[field].last_mut() {
+                Some(bucket) if bucket.start == start => {
+                    bucket.min = bucket.min.min(value);
+                    bucket.max = bucket.max.max(value);
+                    bucket.sum += value;
+                    bucket.count += 1;
+                }
+                _ => buckets[field].push(Bucket {
+                    start,
+                    min: value,
+                    max: value,
+                    sum: value,
+                    count: 1,
+                }),
+            }
+        }
+    }
+
+    history
+        .fields
+        .iter()
+        .zip(buckets)
+        .filter(|(_, buckets)| !buckets.is_empty())
+        .map(|(field, buckets)| (field.clone(), buckets.into_iter().map(Point::from).collect()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(time: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(time).unwrap().with_timezone(&Utc)
+    }
+
+    fn query(since: Option<&str>, until: Option<&str>, step: Option<&str>) -> HistoryQuery {
+        HistoryQuery {
+            since: since.map(String::from),
+            until: until.map(String::from),
+            step: step.map(String::from),
+        }
+    }
+
+    fn fields(values: &[(&str, f64)]) -> Vec<(String, f64)> {
+        values.iter().map(|&(field, value)| (String::from(field), value)).collect()
+    }
+
+    #[test]
+    fn parse_duration_units() {
+        assert_eq!(parse_duration("90"), Some(Duration::seconds(90)));
+        assert_eq!(parse_duration("90s"), Some(Duration::seconds(90)));
+        assert_eq!(parse_duration("5m"), Some(Duration::minutes(5)));
+        assert_eq!(parse_duration("6h"), Some(Duration::hours(6)));
+        assert_eq!(parse_duration("2d"), Some(Duration::days(2)));
+    }
+
+    #[test]
+    fn parse_duration_rejects_bad_input() {
+        for value in ["", "h", "5w", "5 m", "5mm", "-5m", "1.5h", "99999999999999999999s"] {
+            assert_eq!(parse_duration(value), None, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn parse_time_takes_a_timestamp_or_how_long_ago() {
+        let now = at("2024-06-01T12:00:00Z");
+        assert_eq!(parse_time("2024-06-01T10:30:00Z", now), Some(at("2024-06-01T10:30:00Z")));
+        assert_eq!(parse_time("2024-06-01T12:30:00+02:00", now), Some(at("2024-06-01T10:30:00Z")));
+        assert_eq!(parse_time("6h", now), Some(at("2024-06-01T06:00:00Z")));
+        assert_eq!(parse_time("90", now), Some(at("2024-06-01T11:58:30Z")));
+        assert_eq!(parse_time("yesterday", now), None);
+    }
+
+    #[test]
+    fn range_defaults_to_the_window() {
+        let now = at("2024-06-01T12:00:00Z");
+        let (since, until, step) = range(&query(None, None, None), now, Duration::hours(24)).unwrap();
+        assert_eq!(since, at("2024-05-31T12:00:00Z"));
+        assert_eq!(until, now);
+        assert_eq!(step, Duration::seconds(DEFAULT_STEP_S));
+    }
+
+    #[test]
+    fn range_rejects_bad_queries() {
+        let now = at("2024-06-01T12:00:00Z");
+        let window = Duration::hours(24);
+        assert!(range(&query(Some("soon"), None, None), now, window).is_err());
+        assert!(range(&query(None, Some("later"), None), now, window).is_err());
+        assert!(range(&query(None, None, Some("0s")), now, window).is_err());
+        assert!(range(&query(None, None, Some("fast")), now, window).is_err());
+        assert!(range(&query(Some("1h"), Some("2h"), None), now, window).is_err());
+    }
+
+    #[test]
+    fn range_caps_the_number_of_buckets() {
+        let now = at("2024-06-01T12:00:00Z");
+        let window = Duration::hours(24);
+        let limit = format!("{}s", MAX_BUCKETS);
+        assert!(range(&query(Some(&limit), None, Some("1s")), now, window).is_ok());
+        let over = format!("{}s", MAX_BUCKETS + 1);
+        assert_eq!(
+            range(&query(Some(&over), None, Some("1s")), now, window),
+            Err("Too many buckets, use a larger step or a shorter range")
+        );
+        assert!(range(&query(Some(&over), None, Some("2s")), now, window).is_ok());
+    }
+
+    #[test]
+    fn buckets_line_up_with_the_clock() {
+        let mut history = History::new(3600);
+        history.record(at("2024-06-01T12:01:10Z"), fields(&[("temperature", 20.0)]));
+        history.record(at("2024-06-01T12:04:59Z"), fields(&[("temperature", 22.0)]));
+        history.record(at("2024-06-01T12:05:00Z"), fields(&[("temperature", 27.0)]));
+        history.record(at("2024-06-01T12:09:30Z"), fields(&[("temperature", 21.0)]));
+        history.record(at("2024-06-01T12:09:40Z"), fields(&[("temperature", 24.0)]));
+
+        let series = series(&history, at("2024-06-01T12:00:00Z"), at("2024-06-01T12:10:00Z"), Duration::minutes(5));
+        let points = &series["temperature"];
+
+        assert_eq!(points.len(), 2);
+        assert_eq!(points[0].timestamp, "2024-06-01T12:00:00+00:00");
+        assert_eq!((points[0].min, points[0].max, points[0].mean, points[0].count), (20.0, 22.0, 21.0, 2));
+        assert_eq!(points[1].timestamp, "2024-06-01T12:05:00+00:00");
+        assert_eq!((points[1].min, points[1].max, points[1].mean, points[1].count), (21.0, 27.0, 24.0, 3));
+    }
+
+    #[test]
+    fn series_keeps_to_the_range_and_skips_missing_values() {
+        let mut history = History::new(3600);
+        history.record(at("2024-06-01T12:00:00Z"), fields(&[("co2", 400.0)]));
+        history.record(at("2024-06-01T12:01:00Z"), fields(&[("co2", 500.0), ("pressure", 1013.0)]));
+        history.record(at("2024-06-01T12:02:00Z"), fields(&[("co2", 600.0)]));
+
+        let within = series(&history, at("2024-06-01T12:00:30Z"), at("2024-06-01T12:02:00Z"), Duration::hours(1));
+
+        assert_eq!(within["co2"][0].count, 2);
+        assert_eq!(within["co2"][0].mean, 550.0);
+        assert_eq!(within["pressure"][0].count, 1);
+
+        let later = series(&history, at("2024-06-01T13:00:00Z"), at("2024-06-01T14:00:00Z"), Duration::minutes(1));
+        assert!(later.is_empty());
+    }
+
+    #[test]
+    fn readings_fall_out_of_the_window() {
+        let mut history = History::new(60);
+        history.record(at("2024-06-01T12:00:00Z"), fields(&[("lux", 1.0)]));
+        history.record(at("2024-06-01T12:00:30Z"), fields(&[("lux", 2.0)]));
+        history.record(at("2024-06-01T12:01:00Z"), fields(&[("lux", 3.0)]));
+        assert_eq!(history.entries.len(), 3); // Exactly a window old is kept
+
+        history.record(at("2024-06-01T12:01:31Z"), fields(&[("lux", 4.0)]));
+        let kept: Vec<_> = history.entries.iter().map(|entry| entry.values[0]).collect();
+        assert_eq!(kept, [Some(3.0), Some(4.0)]);
+    }
+
+    #[test]
+    fn history_is_off_without_a_window() {
+        assert!(!History::new(0).is_enabled());
+        assert!(History::new(60).is_enabled());
+    }
+}
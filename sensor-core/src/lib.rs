@@ -0,0 +1,30 @@
+// Shared building blocks for the sensor API binaries.
+//
+// A sensor service implements the `Sensor` trait for its driver and hands it to
+// `run`, which loads config.json, samples the sensor on a background thread and
+// serves the latest reading over HTTP, along with a live stream, recent history,
+// health, info and Prometheus metrics endpoints, and optionally publishes it over
+// MQTT. Sensors can add their own endpoints in `Sensor::routes`, protected
+// ones take the `Admin` extractor.
+
+mod auth;
+mod config;
+mod error;
+mod health;
+mod history;
+mod metrics;
+mod mqtt;
+mod psychrometrics;
+mod sampler;
+mod sensor;
+mod server;
+mod stream;
+
+pub use auth::Admin;
+pub use config::{read_or_create_config, MqttConfig, SensorConfig, ServerConfig, CONFIG_PATH};
+pub use error::{error_response, SensorError};
+pub use metrics::LatencyHistogram;
+pub use psychrometrics::Psychrometrics;
+pub use sampler::{Counters, Sample, Sampler, SamplerState};
+pub use sensor::Sensor;
+pub use server::{run, serve, SensorData};
@@ -0,0 +1,132 @@
+// Prometheus text exposition of the latest reading and the sampler's counters
+
+use actix_web::{web, HttpResponse};
+use std::fmt::Write;
+use std::time::Duration;
+
+use crate::health::ServiceInfo;
+use crate::history::numeric_fields;
+use crate::sampler::Sampler;
+use crate::sensor::Sensor;
+
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+// Upper bounds in seconds, a single shot SCD-41 read takes about 5 s
+const LATENCY_BUCKETS: [f64; 13] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+// How long Sensor::read takes, successful or not
+#[derive(Clone, Default)]
+pub struct LatencyHistogram {
+    buckets: [u64; LATENCY_BUCKETS.len()], // Not cumulative, summed up when rendered
+    sum: f64,
+    count: u64,
+}
+
+impl LatencyHistogram {
+    pub fn observe(&mut self, latency: Duration) {
+        let seconds = latency.as_secs_f64();
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.buckets[bucket] += 1;
+        }
+        self.sum += seconds;
+        self.count += 1;
+    }
+}
+
+// Unit suffix for a reading field, following the Prometheus naming conventions
+fn unit(field: &str) -> Option<&'static str> {
+    match field {
+        "temperature" | "dew_point" | "heat_index" | "wet_bulb" => Some("celsius"),
+        "humidity" => Some("percent"),
+        "pressure" | "qnh" | "qff" | "ambient_pressure" => Some("hectopascals"),
+        "altitude" => Some("meters"),
+        "co2" => Some("ppm"),
+        "absolute_humidity" => Some("grams_per_cubic_meter"),
+        "vapour_pressure_deficit" => Some("kilopascals"),
+        "lux" => Some("lux"),
+        _ if field.starts_with("pm") => Some("micrograms_per_cubic_meter"),
+        _ if field.starts_with("particles_") => Some("per_deciliter"),
+        _ => None,
+    }
+}
+
+fn metric_name(field: &str) -> String {
+    match unit(field) {
+        Some(unit) => format!("sensor_{}_{}", field, unit),
+        None => format!("sensor_{}", field),
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+pub(crate) fn routes<S: Sensor>(cfg: &mut web::ServiceConfig) {
+    cfg.route("/metrics", web::get().to(metrics::<S>));
+}
+
+async fn metrics<S: Sensor>(sampler: web::Data<Sampler<S>>, info: web::Data<ServiceInfo>) -> HttpResponse {
+    let labels = format!(
+        "model=\"{}\",bus=\"{}\",address=\"{}\"",
+        escape_label(S::MODEL),
+        escape_label(&info.i2c_bus),
+        info.i2c_address.map(|address| format!("0x{:02x}", address)).unwrap_or_default(),
+    );
+
+    let state = sampler.state();
+    let mut out = String::new();
+
+    // Writing to a String can't fail
+    let mut gauge = |name: &str, help: &str, value: f64| {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} gauge", name);
+        let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+    };
+
+    gauge("sensor_up", "Whether the sensor is initialised", if state.initialised { 1.0 } else { 0.0 });
+
+    // Stale readings are left out, like /sensor_data they would only mislead
+    let latest = state.latest.as_ref().filter(|sample| sample.age() <= sampler.stale_after());
+    if let Some(sample) = latest {
+        gauge(
+            "sensor_reading_age_seconds",
+            "Age of the latest reading",
+            sample.age().as_secs_f64(),
+        );
+
+        // Same fields as /sensor_data
+        for (field, value) in numeric_fields(&sample.reading) {
+            gauge(&metric_name(&field), &format!("Latest {} reading", field), value);
+        }
+    }
+
+    let counters = state.counters;
+    let mut counter = |name: &str, help: &str, value: u64| {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} counter", name);
+        let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+    };
+    counter("sensor_reads_total", "Successful reads", counters.reads);
+    counter("sensor_inits_total", "Successful initialisations", counters.inits);
+    counter("sensor_init_errors_total", "Failed initialisations", counters.init_failures);
+
+    let _ = writeln!(out, "# HELP sensor_read_errors_total Failed reads by error code");
+    let _ = writeln!(out, "# TYPE sensor_read_errors_total counter");
+    for (code, count) in &state.read_errors {
+        let _ = writeln!(out, "sensor_read_errors_total{{{},code=\"{}\"}} {}", labels, code, count);
+    }
+
+    let latency = &state.read_latency;
+    let _ = writeln!(out, "# HELP sensor_read_duration_seconds Time taken by each read");
+    let _ = writeln!(out, "# TYPE sensor_read_duration_seconds histogram");
+    let mut cumulative = 0;
+    for (bound, count) in LATENCY_BUCKETS.iter().zip(latency.buckets) {
+        cumulative += count;
+        let _ = writeln!(out, "sensor_read_duration_seconds_bucket{{{},le=\"{}\"}} {}", labels, bound, cumulative);
+    }
+    let _ = writeln!(out, "sensor_read_duration_seconds_bucket{{{},le=\"+Inf\"}} {}", labels, latency.count);
+    let _ = writeln!(out, "sensor_read_duration_seconds_sum{{{}}} {}", labels, latency.sum);
+    let _ = writeln!(out, "sensor_read_duration_seconds_count{{{}}} {}", labels, latency.count);
+
+    HttpResponse::Ok().content_type(CONTENT_TYPE).body(out)
+}
@@ -0,0 +1,493 @@
+// Publishes each reading to an MQTT broker, with Home Assistant discovery and an availability topic
+
+use log::{debug, info, warn};
+use rumqttc::{Client, Connection, Event, LastWill, MqttOptions, Packet, QoS};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tokio::sync::broadcast::{error::RecvError, Receiver};
+
+use crate::config::{MqttConfig, ServerConfig};
+
+// Requests that can queue up while the broker is unreachable, readings are dropped beyond that
+const REQUEST_CAPACITY: usize = 32;
+
+// Wait before reconnecting after the connection drops
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+
+// Topics derived from the config
+#[derive(Clone)]
+struct Topics {
+    node_id: String,
+    state: String,
+    availability: String,
+    discovery_prefix: Option<String>,
+}
+
+// Home Assistant device_class and unit_of_measurement for a reading field
+fn device_class(field: &str) -> (Option<&'static str>, Option<&'static str>) {
+    match field {
+        "temperature" | "dew_point" | "heat_index" | "wet_bulb" => (Some("temperature"), Some("°C")),
+        "humidity" => (Some("humidity"), Some("%")),
+        "pressure" | "qnh" | "qff" | "ambient_pressure" => (Some("atmospheric_pressure"), Some("hPa")),
+        "vapour_pressure_deficit" => (Some("pressure"), Some("kPa")),
+        "absolute_humidity" => (None, Some("g/m³")),
+        "altitude" => (Some("distance"), Some("m")),
+        "co2" => (Some("carbon_dioxide"), Some("ppm")),
+        "lux" => (Some("illuminance"), Some("lx")),
+        "aqi" | "aqi_pm2_5" | "aqi_pm10" | "caqi" => (Some("aqi"), None),
+        _ if field.starts_with("pm1_0") => (Some("pm1"), Some("µg/m³")),
+        _ if field.starts_with("pm2_5") => (Some("pm25"), Some("µg/m³")),
+        _ if field.starts_with("pm10") => (Some("pm10"), Some("µg/m³")),
+        _ if field.starts_with("particles_") => (None, Some("particles/0.1L")),
+        _ if field.ends_with("_ms") => (Some("duration"), Some("ms")),
+        _ => (None, None),
+    }
+}
+
+// temperature -> Temperature, pm2_5_env -> Pm2 5 env
+fn display_name(field: &str) -> String {
+    let name = field.replace('_', " ");
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => name,
+    }
+}
+
+// Topics and IDs only allow a safe subset of characters
+fn sanitise(id: &str) -> String {
+    id.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c.to_ascii_lowercase() } else { '_' })
+        .collect()
+}
+
+#[derive(Serialize)]
+struct Device<'a> {
+    identifiers: [&'a str; 1],
+    name: String,
+    model: &'static str,
+    sw_version: &'static str,
+}
+
+// Payload of a homeassistant/sensor/<node_id>/<field>/config message
+#[derive(Serialize)]
+struct Discovery<'a> {
+    name: String,
+    unique_id: String,
+    state_topic: &'a str,
+    value_template: String,
+    availability_topic: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_class: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unit_of_measurement: Option<&'static str>,
+    state_class: &'static str,
+    device: Device<'a>,
+}
+
+// Connect to the broker and publish every reading from `updates` until the sampler stops
+pub(crate) fn start(
+    config: &MqttConfig,
+    server: &ServerConfig,
+    model: &'static str,
+    updates: Receiver<String>,
+) -> std::io::Result<()> {
+    let node_id = sanitise(
+        &config
+            .node_id
+            .clone()
+            .unwrap_or_else(|| format!("{}_{}", model, server.network_port)),
+    );
+    let base = config.topic.clone().unwrap_or_else(|| format!("sensor-api/{}", node_id));
+    let topics = Topics {
+        state: format!("{}/state", base),
+        availability: format!("{}/availability", base),
+        discovery_prefix: config.discovery_prefix.clone(),
+        node_id,
+    };
+
+    let mut options = MqttOptions::new(format!("sensor-api-{}", topics.node_id), &config.host, config.port);
+    options.set_keep_alive(KEEP_ALIVE);
+    if let Some(username) = &config.username {
+        options.set_credentials(username, config.password.clone().unwrap_or_default());
+    }
+    // The broker marks the sensor unavailable if the service goes away without saying so
+    options.set_last_will(LastWill::new(&topics.availability, "offline", QoS::AtLeastOnce, true));
+
+    let (client, connection) = Client::new(options, REQUEST_CAPACITY);
+    let connected = Arc::new(AtomicBool::new(false)); // Set on every (re)connect, discovery is sent again after it
+
+    info!("Publishing readings to MQTT broker {}:{} on {}", config.host, config.port, topics.state);
+
+    let connection_client = client.clone();
+    let connection_topics = topics.clone();
+    let connection_connected = Arc::clone(&connected);
+    thread::Builder::new()
+        .name(String::from("mqtt-connection"))
+        .spawn(move || drive(connection, connection_client, connection_topics, connection_connected))?;
+
+    thread::Builder::new()
+        .name(String::from("mqtt-publisher"))
+        .spawn(move || publish_loop(client, topics, model, connected, updates))?;
+
+    Ok(())
+}
+
+// rumqttc only makes progress while the connection is polled, it reconnects on the next poll after an error
+fn drive(mut connection: Connection, client: Client, topics: Topics, connected: Arc<AtomicBool>) {
+    for event in connection.iter() {
+        match event {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                info!("Connected to MQTT broker");
+                if let Err(e) = client.try_publish(&topics.availability, QoS::AtLeastOnce, true, "online") {
+                    warn!("Failed to publish MQTT availability: {}", e);
+                }
+                connected.store(true, Ordering::Relaxed);
+            }
+            Ok(_) => {}
+            Err(e) => {
+                warn!("MQTT connection failed: {}, retrying in {:?}", e, RECONNECT_DELAY);
+                thread::sleep(RECONNECT_DELAY);
+            }
+        }
+    }
+}
+
+fn publish_loop(
+    client: Client,
+    topics: Topics,
+    model: &'static str,
+    connected: Arc<AtomicBool>,
+    mut updates: Receiver<String>,
+) {
+    let mut announced = BTreeSet::new();
+    let mut discover_fields = false; // Waits for the first connection, so discovery isn't sent twice
+
+    loop {
+        let json = match updates.blocking_recv() {
+            Ok(json) => json,
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => return, // The sampler has gone
+        };
+
+        if connected.swap(false, Ordering::Relaxed) {
+            announced.clear();
+            discover_fields = true;
+        }
+
+        // Fields can come and go, such as psychrometrics or sea level pressure, so check every reading
+        if let Some(prefix) = topics.discovery_prefix.as_ref().filter(|_| discover_fields) {
+            if let Ok(Value::Object(fields)) = serde_json::from_str::<Value>(&json) {
+                for (field, value) in &fields {
+                    if value.is_number() && field != "age_ms" && !announced.contains(field) {
+                        discover(&client, &topics, prefix, model, field);
+                        announced.insert(field.clone());
+                    }
+                }
+            }
+        }
+
+        if let Err(e) = client.try_publish(&topics.state, QoS::AtMostOnce, false, json) {
+            debug!("Dropped MQTT reading: {}", e);
+        }
+    }
+}
+
+fn discover(client: &Client, topics: &Topics, prefix: &str, model: &'static str, field: &str) {
+    let (topic, discovery) = discovery(topics, prefix, model, field);
+    match serde_json::to_vec(&discovery) {
+        Ok(payload) => {
+            if let Err(e) = client.try_publish(topic, QoS::AtLeastOnce, true, payload) {
+                warn!("Failed to publish Home Assistant discovery for {}: {}", field, e);
+            }
+        }
+        Err(e) => warn!("Failed to serialise Home Assistant discovery for {}: {}", field, e),
+    }
+}
+
+// Discovery topic and payload for one field
+fn discovery<'a>(topics: &'a Topics, prefix: &str, model: &'static str, field: &str) -> (String, Discovery<'a>) {
+    let (device_class, unit_of_measurement) = device_class(field);
+    let discovery = Discovery {
+        name: display_name(field),
+        unique_id: format!("{}_{}", topics.node_id, field),
+        state_topic: &topics.state,
+        value_template: format!("{{{{ value_json.{} }}}}", field),
+        availability_topic: &topics.availability,
+        device_class,
+        unit_of_measurement,
+        state_class: "measurement",
+        device: Device {
+            identifiers: [&topics.node_id],
+            name: format!("{} {}", model, topics.node_id),
+            model,
+            sw_version: env!("CARGO_PKG_VERSION"),
+        },
+    };
+
+    (format!("{}/sensor/{}/{}/config", prefix, topics.node_id, field), discovery)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Mutex;
+    use std::time::Instant;
+    use tokio::sync::broadcast;
+
+    fn topics() -> Topics {
+        Topics {
+            node_id: String::from("bme280_5000"),
+            state: String::from("sensor-api/bme280_5000/state"),
+            availability: String::from("sensor-api/bme280_5000/availability"),
+            discovery_prefix: Some(String::from("homeassistant")),
+        }
+    }
+
+    #[test]
+    fn device_class_for_known_fields() {
+        assert_eq!(device_class("temperature"), (Some("temperature"), Some("°C")));
+        assert_eq!(device_class("dew_point"), (Some("temperature"), Some("°C")));
+        assert_eq!(device_class("humidity"), (Some("humidity"), Some("%")));
+        assert_eq!(device_class("qnh"), (Some("atmospheric_pressure"), Some("hPa")));
+        assert_eq!(device_class("vapour_pressure_deficit"), (Some("pressure"), Some("kPa")));
+        assert_eq!(device_class("absolute_humidity"), (None, Some("g/m³")));
+        assert_eq!(device_class("co2"), (Some("carbon_dioxide"), Some("ppm")));
+        assert_eq!(device_class("lux"), (Some("illuminance"), Some("lx")));
+        assert_eq!(device_class("caqi"), (Some("aqi"), None));
+    }
+
+    #[test]
+    fn device_class_by_prefix_and_suffix() {
+        assert_eq!(device_class("pm1_0_env"), (Some("pm1"), Some("µg/m³")));
+        assert_eq!(device_class("pm2_5_standard"), (Some("pm25"), Some("µg/m³")));
+        assert_eq!(device_class("pm10_env"), (Some("pm10"), Some("µg/m³")));
+        assert_eq!(device_class("particles_03um"), (None, Some("particles/0.1L")));
+        assert_eq!(device_class("integration_time_ms"), (Some("duration"), Some("ms")));
+    }
+
+    #[test]
+    fn scaled_and_raw_counts_have_no_unit() {
+        // The VEML7700 white channel is scaled like ALS but isn't an illuminance
+        assert_eq!(device_class("white"), (None, None));
+        assert_eq!(device_class("raw_als"), (None, None));
+        assert_eq!(device_class("uv_index"), (None, None));
+    }
+
+    #[test]
+    fn sanitise_keeps_a_safe_lowercase_subset() {
+        assert_eq!(sanitise("BME280_5000"), "bme280_5000");
+        assert_eq!(sanitise("scd-41"), "scd-41");
+        assert_eq!(sanitise("living room/1+#"), "living_room_1__");
+        assert_eq!(sanitise("µg"), "_g");
+    }
+
+    #[test]
+    fn display_name_capitalises_the_first_word() {
+        assert_eq!(display_name("temperature"), "Temperature");
+        assert_eq!(display_name("pm2_5_env"), "Pm2 5 env");
+        assert_eq!(display_name(""), "");
+    }
+
+    #[test]
+    fn discovery_topic_and_payload() {
+        let topics = topics();
+        let (topic, discovery) = discovery(&topics, "homeassistant", "BME280", "pressure");
+
+        assert_eq!(topic, "homeassistant/sensor/bme280_5000/pressure/config");
+        assert_eq!(
+            serde_json::to_value(discovery).unwrap(),
+            json!({
+                "name": "Pressure",
+                "unique_id": "bme280_5000_pressure",
+                "state_topic": "sensor-api/bme280_5000/state",
+                "value_template": "{{ value_json.pressure }}",
+                "availability_topic": "sensor-api/bme280_5000/availability",
+                "device_class": "atmospheric_pressure",
+                "unit_of_measurement": "hPa",
+                "state_class": "measurement",
+                "device": {
+                    "identifiers": ["bme280_5000"],
+                    "name": "BME280 bme280_5000",
+                    "model": "BME280",
+                    "sw_version": env!("CARGO_PKG_VERSION"),
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn discovery_leaves_out_an_unknown_class_and_unit() {
+        let topics = topics();
+        let payload = serde_json::to_value(discovery(&topics, "homeassistant", "VEML7700", "white").1).unwrap();
+
+        assert!(payload.get("device_class").is_none());
+        assert!(payload.get("unit_of_measurement").is_none());
+        assert_eq!(payload["state_class"], "measurement");
+    }
+
+    // What the broker stand-in saw
+    #[derive(Default)]
+    struct Broker {
+        will: Option<(String, Vec<u8>, bool)>, // Topic, message, retain
+        publishes: Vec<Publish>,
+    }
+
+    #[derive(Clone)]
+    struct Publish {
+        topic: String,
+        payload: Vec<u8>,
+        qos: u8,
+        retain: bool,
+    }
+
+    impl Broker {
+        fn find(&self, topic: &str) -> Option<Publish> {
+            self.publishes.iter().find(|publish| publish.topic == topic).cloned()
+        }
+    }
+
+    fn read_packet(stream: &mut TcpStream) -> std::io::Result<(u8, Vec<u8>)> {
+        let mut header = [0; 1];
+        stream.read_exact(&mut header)?;
+        let (mut length, mut shift) = (0, 0);
+        loop {
+            let mut byte = [0; 1];
+            stream.read_exact(&mut byte)?;
+            length |= ((byte[0] & 0x7F) as usize) << shift;
+            shift += 7;
+            if byte[0] & 0x80 == 0 {
+                break;
+            }
+        }
+        let mut body = vec![0; length];
+        stream.read_exact(&mut body)?;
+        Ok((header[0], body))
+    }
+
+    // A length prefixed MQTT string or binary field
+    fn field<'a>(body: &'a [u8], at: &mut usize) -> &'a [u8] {
+        let length = u16::from_be_bytes([body[*at], body[*at + 1]]) as usize;
+        *at += 2 + length;
+        &body[*at - length..*at]
+    }
+
+    // Just enough of an MQTT 3.1.1 broker for one client: accepts the connection, acknowledges
+    // QoS 1 publishes and records what it was sent
+    fn broker_stand_in() -> (u16, Arc<Mutex<Broker>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let broker = Arc::new(Mutex::new(Broker::default()));
+
+        let seen = Arc::clone(&broker);
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            while let Ok((header, body)) = read_packet(&mut stream) {
+                match header >> 4 {
+                    1 => {
+                        // CONNECT: protocol name, level, flags, keep alive, then the client id
+                        let mut at = 0;
+                        field(&body, &mut at);
+                        let flags = body[at + 1];
+                        at += 4;
+                        field(&body, &mut at);
+                        if flags & 0x04 != 0 {
+                            let topic = String::from_utf8(field(&body, &mut at).to_vec()).unwrap();
+                            let message = field(&body, &mut at).to_vec();
+                            seen.lock().unwrap().will = Some((topic, message, flags & 0x20 != 0));
+                        }
+                        stream.write_all(&[0x20, 0x02, 0x00, 0x00]).unwrap();
+                    }
+                    3 => {
+                        let qos = (header >> 1) & 0x03;
+                        let mut at = 0;
+                        let topic = String::from_utf8(field(&body, &mut at).to_vec()).unwrap();
+                        if qos > 0 {
+                            stream.write_all(&[0x40, 0x02, body[at], body[at + 1]]).unwrap();
+                            at += 2;
+                        }
+                        seen.lock().unwrap().publishes.push(Publish {
+                            topic,
+                            payload: body[at..].to_vec(),
+                            qos,
+                            retain: header & 0x01 != 0,
+                        });
+                    }
+                    12 => stream.write_all(&[0xD0, 0x00]).unwrap(), // PINGREQ
+                    _ => {}
+                }
+            }
+        });
+
+        (port, broker)
+    }
+
+    #[test]
+    fn publishes_availability_discovery_and_readings_to_the_broker() {
+        let (port, broker) = broker_stand_in();
+        let config = MqttConfig {
+            host: String::from("127.0.0.1"),
+            port,
+            username: None,
+            password: None,
+            node_id: Some(String::from("Test Node")),
+            topic: None,
+            discovery_prefix: Some(String::from("homeassistant")),
+        };
+        let (updates, receiver) = broadcast::channel(16);
+        start(&config, &ServerConfig::with_port(5000), "BME280", receiver).unwrap();
+
+        // Readings are only announced once the connection is up, so keep sending until they are
+        let reading = r#"{"timestamp":"2024-06-01T12:00:00+00:00","model":"BME280","age_ms":5,"pressure":1013.2}"#;
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while broker.lock().unwrap().find("homeassistant/sensor/test_node/pressure/config").is_none() {
+            assert!(Instant::now() < deadline, "No discovery message from the publisher");
+            updates.send(String::from(reading)).unwrap();
+            thread::sleep(Duration::from_millis(50));
+        }
+        updates.send(String::from(reading)).unwrap();
+        while broker.lock().unwrap().find("sensor-api/test_node/state").is_none() {
+            assert!(Instant::now() < deadline, "No reading from the publisher");
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let broker = broker.lock().unwrap();
+        let (will_topic, will_message, will_retain) = broker.will.clone().expect("CONNECT had no will");
+        assert_eq!(will_topic, "sensor-api/test_node/availability");
+        assert_eq!(will_message, b"offline");
+        assert!(will_retain);
+
+        let online = broker.find("sensor-api/test_node/availability").unwrap();
+        assert_eq!((online.payload.as_slice(), online.qos, online.retain), (&b"online"[..], 1, true));
+
+        let discovery = broker.find("homeassistant/sensor/test_node/pressure/config").unwrap();
+        assert_eq!((discovery.qos, discovery.retain), (1, true));
+        let payload: Value = serde_json::from_slice(&discovery.payload).unwrap();
+        assert_eq!(payload["state_topic"], "sensor-api/test_node/state");
+        assert_eq!(payload["availability_topic"], "sensor-api/test_node/availability");
+        assert_eq!(payload["device_class"], "atmospheric_pressure");
+
+        // Only numeric fields are announced, and age_ms isn't worth a sensor of its own
+        let announced: BTreeSet<_> = broker
+            .publishes
+            .iter()
+            .filter(|publish| publish.topic.ends_with("/config"))
+            .map(|publish| publish.topic.as_str())
+            .collect();
+        assert_eq!(announced, BTreeSet::from(["homeassistant/sensor/test_node/pressure/config"]));
+
+        let state = broker.find("sensor-api/test_node/state").unwrap();
+        assert_eq!(state.payload, reading.as_bytes());
+        assert_eq!((state.qos, state.retain), (0, false));
+    }
+}
@@ -0,0 +1,186 @@
+// Values derived from air temperature (°C) and relative humidity (%), for sensors that report both
+
+use serde::Serialize;
+
+// Magnus coefficients over water (Alduchov and Eskridge 1996), good from -40 to 50 °C
+const MAGNUS_A: f32 = 6.1094; // hPa
+const MAGNUS_B: f32 = 17.625;
+const MAGNUS_C: f32 = 243.04; // °C
+
+const KELVIN: f32 = 273.15;
+
+// Grams of water per m³ for each hPa of vapour pressure at 1 K, 100 / 461.5 J/(kg·K) * 1000
+const ABSOLUTE_HUMIDITY_FACTOR: f32 = 216.68;
+
+// Added next to temperature and humidity when include_psychrometrics is set
+#[derive(Serialize, Clone, Copy)]
+pub struct Psychrometrics {
+    pub dew_point: f32,               // °C
+    pub absolute_humidity: f32,       // g/m³
+    pub vapour_pressure_deficit: f32, // kPa
+    pub heat_index: f32,              // °C, what it feels like in the shade
+    pub humidex: f32,                 // Canadian feels-like index
+    pub wet_bulb: f32,                // °C, at sea level pressure
+}
+
+impl Psychrometrics {
+    pub fn new(temperature: f32, humidity: f32) -> Self {
+        // Dew point is undefined at 0% and sensors can read slightly over 100%
+        let humidity = humidity.clamp(0.1, 100.0);
+        let saturation = saturation_vapour_pressure(temperature);
+        let vapour = saturation * humidity / 100.0;
+
+        Psychrometrics {
+            dew_point: dew_point(temperature, humidity),
+            absolute_humidity: ABSOLUTE_HUMIDITY_FACTOR * vapour / (temperature + KELVIN),
+            vapour_pressure_deficit: (saturation - vapour) / 10.0,
+            heat_index: heat_index(temperature, humidity),
+            humidex: temperature + 0.5555 * (vapour - 10.0),
+            wet_bulb: wet_bulb(temperature, humidity),
+        }
+    }
+}
+
+// hPa
+fn saturation_vapour_pressure(temperature: f32) -> f32 {
+    MAGNUS_A * (MAGNUS_B * temperature / (MAGNUS_C + temperature)).exp()
+}
+
+fn dew_point(temperature: f32, humidity: f32) -> f32 {
+    let gamma = (humidity / 100.0).ln() + MAGNUS_B * temperature / (MAGNUS_C + temperature);
+    MAGNUS_C * gamma / (MAGNUS_B - gamma)
+}
+
+// US National Weather Service heat index, the Rothfusz regression with its adjustments
+fn heat_index(temperature: f32, humidity: f32) -> f32 {
+    // The formula works in °F, f64 keeps the published coefficients intact
+    let t = temperature as f64 * 1.8 + 32.0;
+    let rh = humidity as f64;
+
+    // The simple formula is used when the result is below 80 °F
+    let simple = 0.5 * (t + 61.0 + (t - 68.0) * 1.2 + rh * 0.094);
+    let hi = if (simple + t) / 2.0 < 80.0 {
+        simple
+    } else {
+        let mut hi = -42.379 + 2.04901523 * t + 10.14333127 * rh
+            - 0.22475541 * t * rh
+            - 0.00683783 * t * t
+            - 0.05481717 * rh * rh
+            + 0.00122874 * t * t * rh
+            + 0.00085282 * t * rh * rh
+            - 0.00000199 * t * t * rh * rh;
+
+        if rh < 13.0 && (80.0..=112.0).contains(&t) {
+            hi -= (13.0 - rh) / 4.0 * ((17.0 - (t - 95.0).abs()) / 17.0).sqrt();
+        } else if rh > 85.0 && (80.0..=87.0).contains(&t) {
+            hi += (rh - 85.0) / 10.0 * ((87.0 - t) / 5.0);
+        }
+        hi
+    };
+
+    ((hi - 32.0) / 1.8) as f32
+}
+
+// Stull (2011), within 1 °C between 5% and 99% humidity and -20 to 50 °C
+fn wet_bulb(temperature: f32, humidity: f32) -> f32 {
+    let t = temperature;
+    let rh = humidity;
+    t * (0.151977 * (rh + 8.313659).sqrt()).atan() + (t + rh).atan() - (rh - 1.676331).atan()
+        + 0.00391838 * rh.powf(1.5) * (0.023101 * rh).atan()
+        - 4.686035
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f32, expected: f32, tolerance: f32) {
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "{} is not within {} of {}",
+            actual,
+            tolerance,
+            expected
+        );
+    }
+
+    fn fahrenheit_to_celsius(f: f32) -> f32 {
+        (f - 32.0) / 1.8
+    }
+
+    #[test]
+    fn dew_point_matches_reference_values() {
+        assert_close(Psychrometrics::new(25.0, 50.0).dew_point, 13.9, 0.1);
+        assert_close(Psychrometrics::new(30.0, 70.0).dew_point, 23.9, 0.1);
+        assert_close(Psychrometrics::new(0.0, 50.0).dew_point, -9.2, 0.1);
+        assert_close(Psychrometrics::new(20.0, 100.0).dew_point, 20.0, 0.01);
+    }
+
+    #[test]
+    fn dew_point_is_defined_at_the_humidity_extremes() {
+        assert!(Psychrometrics::new(20.0, 0.0).dew_point.is_finite());
+        assert_close(Psychrometrics::new(20.0, 102.0).dew_point, 20.0, 0.01);
+    }
+
+    #[test]
+    fn absolute_humidity_of_saturated_air() {
+        assert_close(Psychrometrics::new(20.0, 100.0).absolute_humidity, 17.3, 0.1);
+        assert_close(Psychrometrics::new(30.0, 100.0).absolute_humidity, 30.4, 0.2);
+        assert_close(Psychrometrics::new(25.0, 50.0).absolute_humidity, 11.5, 0.1);
+    }
+
+    #[test]
+    fn vapour_pressure_deficit_matches_reference_values() {
+        assert_close(Psychrometrics::new(25.0, 50.0).vapour_pressure_deficit, 1.58, 0.01);
+        assert_close(Psychrometrics::new(30.0, 30.0).vapour_pressure_deficit, 2.97, 0.01);
+        assert_close(Psychrometrics::new(20.0, 100.0).vapour_pressure_deficit, 0.0, 0.001);
+    }
+
+    #[test]
+    fn heat_index_matches_the_nws_table() {
+        // Rothfusz regression, the table rounds to whole °F
+        let table = [(90.0, 60.0, 100.0), (100.0, 40.0, 109.0), (96.0, 50.0, 108.0), (84.0, 70.0, 90.0)];
+        for (t, rh, expected) in table {
+            let heat_index = Psychrometrics::new(fahrenheit_to_celsius(t), rh).heat_index;
+            assert_close(heat_index, fahrenheit_to_celsius(expected), 0.5);
+        }
+    }
+
+    #[test]
+    fn heat_index_simple_formula_below_80_f() {
+        // 0.5 * (70 + 61 + (70 - 68) * 1.2 + 50 * 0.094) = 69.05 °F
+        let heat_index = Psychrometrics::new(fahrenheit_to_celsius(70.0), 50.0).heat_index;
+        assert_close(heat_index, fahrenheit_to_celsius(69.05), 0.01);
+    }
+
+    #[test]
+    fn heat_index_low_humidity_adjustment() {
+        // The regression alone gives 94.8 °F, the adjustment takes 0.6 °F off
+        let heat_index = Psychrometrics::new(fahrenheit_to_celsius(100.0), 10.0).heat_index;
+        assert_close(heat_index, fahrenheit_to_celsius(94.1), 0.05);
+    }
+
+    #[test]
+    fn heat_index_high_humidity_adjustment() {
+        // The NWS table gives 105 °F at 86 °F and 90%, the adjustment adds 0.1 °F
+        let heat_index = Psychrometrics::new(fahrenheit_to_celsius(86.0), 90.0).heat_index;
+        assert_close(heat_index, fahrenheit_to_celsius(105.4), 0.05);
+        assert_close(heat_index, fahrenheit_to_celsius(105.0), 0.5);
+    }
+
+    #[test]
+    fn humidex_matches_the_environment_canada_table() {
+        assert_close(Psychrometrics::new(30.0, 70.0).humidex, 41.0, 0.5);
+        assert_close(Psychrometrics::new(35.0, 50.0).humidex, 45.0, 0.5);
+        assert_close(Psychrometrics::new(25.0, 60.0).humidex, 30.0, 0.5);
+    }
+
+    #[test]
+    fn wet_bulb_matches_stull() {
+        // Stull's worked example
+        assert_close(Psychrometrics::new(20.0, 50.0).wet_bulb, 13.7, 0.05);
+        // Saturated air is at its wet bulb temperature, within the formula's 1 °C accuracy
+        assert_close(Psychrometrics::new(25.0, 99.0).wet_bulb, 25.0, 1.0);
+        assert!(Psychrometrics::new(30.0, 40.0).wet_bulb < 30.0);
+    }
+}
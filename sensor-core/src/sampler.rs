@@ -0,0 +1,264 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use log::{error, info, warn};
+use std::collections::BTreeMap;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, oneshot};
+
+use crate::config::SensorConfig;
+use crate::error::SensorError;
+use crate::history::{numeric_fields, History};
+use crate::metrics::LatencyHistogram;
+use crate::sensor::Sensor;
+use crate::server::SensorData;
+
+// Readings older than this many sample intervals are not served
+const STALE_AFTER_INTERVALS: u32 = 3;
+
+// Read failures in a row before the sensor is dropped and initialised again
+const MAX_CONSECUTIVE_FAILURES: u32 = 3;
+
+// Readings a slow /sensor_data/stream client can fall behind by before it skips ahead
+const UPDATES_CAPACITY: usize = 16;
+
+// Work sent from HTTP handlers to run on the sampler thread between reads
+type Command<S> = Box<dyn FnOnce(&mut S) + Send>;
+
+// A reading along with when it was taken
+pub struct Sample<R> {
+    pub reading: R,
+    pub timestamp: DateTime<Utc>,
+    taken_at: Instant,
+}
+
+impl<R> Sample<R> {
+    pub fn age(&self) -> Duration {
+        self.taken_at.elapsed()
+    }
+}
+
+// Running totals since the service started
+#[derive(Serialize, Clone, Copy, Default)]
+pub struct Counters {
+    pub reads: u64,
+    pub read_failures: u64,
+    pub inits: u64,
+    pub init_failures: u64,
+}
+
+// What the HTTP handlers can see of the sampler
+pub struct SamplerState<R> {
+    pub latest: Option<Sample<R>>,
+    pub last_error: Option<SensorError>,
+    pub initialised: bool,
+    pub counters: Counters,
+    pub read_errors: BTreeMap<&'static str, u64>, // Failed reads by error code
+    pub read_latency: LatencyHistogram,
+}
+
+// Owns the sensor on a dedicated thread and keeps the latest reading in shared state
+pub struct Sampler<S: Sensor> {
+    state: Arc<RwLock<SamplerState<S::Reading>>>,
+    commands: Sender<Command<S>>,
+    updates: broadcast::Sender<String>, // Each new reading as /sensor_data JSON
+    history: Arc<Mutex<History>>,
+    interval: Duration,
+    started: Instant,
+}
+
+impl<S: Sensor> Sampler<S> {
+    // Spawn the sampling thread, the sensor is initialised (and re-initialised) there
+    pub fn start(config: S::Config) -> std::io::Result<Self> {
+        let interval = Duration::from_millis(config.server().sample_interval_ms.max(1))
+            .max(S::min_sample_interval(&config));
+        let state = Arc::new(RwLock::new(SamplerState {
+            latest: None,
+            last_error: None,
+            initialised: false,
+            counters: Counters::default(),
+            read_errors: BTreeMap::new(),
+            read_latency: LatencyHistogram::default(),
+        }));
+        let (commands, command_rx) = mpsc::channel();
+        let (updates, _) = broadcast::channel(UPDATES_CAPACITY);
+        let history = Arc::new(Mutex::new(History::new(config.server().history_window_s)));
+
+        let thread_state = Arc::clone(&state);
+        let thread_updates = updates.clone();
+        let thread_history = Arc::clone(&history);
+        thread::Builder::new()
+            .name(format!("{}-sampler", S::MODEL))
+            .spawn(move || {
+                sample_loop::<S>(config, interval, thread_state, command_rx, thread_updates, thread_history)
+            })?;
+
+        Ok(Sampler {
+            state,
+            commands,
+            updates,
+            history,
+            interval,
+            started: Instant::now(),
+        })
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    // How old a reading can get before it is no longer served
+    pub fn stale_after(&self) -> Duration {
+        self.interval * STALE_AFTER_INTERVALS
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    // Receive every new reading as it is taken
+    pub fn subscribe(&self) -> broadcast::Receiver<String> {
+        self.updates.subscribe()
+    }
+
+    pub(crate) fn history(&self) -> MutexGuard<'_, History> {
+        self.history.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn state(&self) -> RwLockReadGuard<'_, SamplerState<S::Reading>> {
+        self.state.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Run `f` against the sensor on the sampler thread, so it never races a read
+    pub async fn execute<T, F>(&self, f: F) -> Result<T, SensorError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut S) -> Result<T, SensorError> + Send + 'static,
+    {
+        let (reply, response) = oneshot::channel();
+        let command: Command<S> = Box::new(move |sensor| {
+            let _ = reply.send(f(sensor));
+        });
+
+        if self.commands.send(command).is_err() {
+            return Err(SensorError::Command(String::from("Sampler is not running")));
+        }
+
+        // The command is dropped unrun if the sensor isn't initialised
+        match response.await {
+            Ok(result) => result,
+            Err(_) => Err(SensorError::NotReady(String::from("Sensor is not initialised"))),
+        }
+    }
+}
+
+fn sample_loop<S: Sensor>(
+    config: S::Config,
+    interval: Duration,
+    state: Arc<RwLock<SamplerState<S::Reading>>>,
+    commands: Receiver<Command<S>>,
+    updates: broadcast::Sender<String>,
+    history: Arc<Mutex<History>>,
+) {
+    let mut sensor: Option<S> = None;
+    let mut failures = 0;
+
+    loop {
+        let started = Instant::now();
+
+        if sensor.is_none() {
+            match S::init(&config) {
+                Ok(s) => {
+                    info!("{} initialised, sampling every {:?}", S::MODEL, interval);
+                    sensor = Some(s);
+                    failures = 0;
+
+                    let mut state = state.write().unwrap_or_else(|e| e.into_inner());
+                    state.initialised = true;
+                    state.counters.inits += 1;
+                }
+                Err(e) => {
+                    error!("{} {}", S::MODEL, e);
+                    let mut state = state.write().unwrap_or_else(|e| e.into_inner());
+                    state.last_error = Some(e);
+                    state.counters.init_failures += 1;
+                }
+            }
+        }
+
+        if let Some(s) = sensor.as_mut() {
+            let read_started = Instant::now();
+            let result = s.read();
+            let latency = read_started.elapsed();
+
+            let mut state = state.write().unwrap_or_else(|e| e.into_inner());
+            state.read_latency.observe(latency);
+            match result {
+                Ok(reading) => {
+                    let timestamp = Utc::now();
+
+                    // Only worth serialising when someone is streaming
+                    if updates.receiver_count() > 0 {
+                        let data = SensorData {
+                            timestamp: timestamp.to_rfc3339(),
+                            model: S::MODEL,
+                            age_ms: 0,
+                            reading: &reading,
+                        };
+                        if let Ok(json) = serde_json::to_string(&data) {
+                            let _ = updates.send(json);
+                        }
+                    }
+
+                    let mut history = history.lock().unwrap_or_else(|e| e.into_inner());
+                    if history.is_enabled() {
+                        history.record(timestamp, numeric_fields(&reading));
+                    }
+                    drop(history);
+
+                    state.latest = Some(Sample {
+                        reading,
+                        timestamp,
+                        taken_at: Instant::now(),
+                    });
+                    state.last_error = None;
+                    state.counters.reads += 1;
+                    failures = 0;
+                }
+                Err(e) => {
+                    error!("{} {}", S::MODEL, e);
+                    *state.read_errors.entry(e.code()).or_default() += 1;
+                    state.last_error = Some(e);
+                    state.counters.read_failures += 1;
+
+                    failures += 1;
+                    if failures >= MAX_CONSECUTIVE_FAILURES {
+                        warn!("{} failed {} reads in a row, re-initialising", S::MODEL, failures);
+                        sensor = None;
+                        state.initialised = false;
+                    }
+                }
+            }
+        }
+
+        // Wait for the next sample, running any commands that arrive in the meantime
+        let next_sample = started + interval;
+        loop {
+            let remaining = next_sample.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break;
+            }
+            match commands.recv_timeout(remaining) {
+                Ok(command) => {
+                    if let Some(s) = sensor.as_mut() {
+                        command(s);
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => return, // The Sampler was dropped
+            }
+        }
+    }
+}
@@ -0,0 +1,37 @@
+use actix_web::web;
+use serde::Serialize;
+use std::time::Duration;
+
+use crate::config::SensorConfig;
+use crate::error::SensorError;
+
+// A sensor driver the shared server knows how to set up and read
+pub trait Sensor: Sized + Send + 'static {
+    // config.json contents for this sensor
+    type Config: SensorConfig;
+    // Measurement fields, flattened into the /sensor_data response
+    type Reading: Serialize + Send + Sync + 'static;
+
+    // Reported as "model" in every response
+    const MODEL: &'static str;
+
+    // Open the bus and bring the sensor into a state where it can be read
+    fn init(config: &Self::Config) -> Result<Self, SensorError>;
+
+    // Take a single reading, called from the sampler thread every sample_interval_ms
+    fn read(&mut self) -> Result<Self::Reading, SensorError>;
+
+    // How often the sensor produces new data, the sampler never reads faster than this
+    fn min_sample_interval(_config: &Self::Config) -> Duration {
+        Duration::ZERO
+    }
+
+    // Chip details for /sensor_info such as serial number, firmware or settings read back
+    fn device_info(&mut self) -> Result<serde_json::Value, SensorError> {
+        Ok(serde_json::Value::Null)
+    }
+
+    // Extra endpoints for this sensor, handlers can take web::Data<Sampler<Self>> and
+    // use Sampler::execute to talk to the sensor, or web::Data<Self::Config> for its settings
+    fn routes(_cfg: &mut web::ServiceConfig) {}
+}
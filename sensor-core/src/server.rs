@@ -0,0 +1,90 @@
+use actix_web::error::InternalError;
+use actix_web::http::StatusCode;
+use actix_web::{middleware::Logger, web, App, HttpResponse, HttpServer};
+use env_logger::Env;
+use serde::Serialize;
+
+use crate::config::{read_or_create_config, SensorConfig};
+use crate::error::{error_response, set_sensor_model, SensorError};
+use crate::health::{self, ServiceInfo};
+use crate::history;
+use crate::metrics;
+use crate::mqtt;
+use crate::sampler::Sampler;
+use crate::sensor::Sensor;
+use crate::stream;
+
+// Structure returned by /sensor_data, the reading's fields sit next to timestamp and model
+#[derive(Serialize)]
+pub struct SensorData<'a, R> {
+    pub timestamp: String,
+    pub model: &'static str,
+    pub age_ms: u64, // How long ago the sampler took this reading
+    #[serde(flatten)]
+    pub reading: &'a R,
+}
+
+async fn get_sensor_data<S: Sensor>(sampler: web::Data<Sampler<S>>) -> Result<HttpResponse, SensorError> {
+    let state = sampler.state();
+    let stale_after = sampler.stale_after();
+
+    match &state.latest {
+        Some(sample) if sample.age() <= stale_after => Ok(HttpResponse::Ok().json(SensorData {
+            timestamp: sample.timestamp.to_rfc3339(),
+            model: S::MODEL,
+            age_ms: sample.age().as_millis() as u64,
+            reading: &sample.reading,
+        })),
+        _ => Err(state
+            .last_error
+            .clone()
+            .unwrap_or_else(|| SensorError::NotReady(String::from("No reading available yet")))),
+    }
+}
+
+// Malformed request bodies get the same error format as everything else
+fn json_error(err: actix_web::error::JsonPayloadError, _: &actix_web::HttpRequest) -> actix_web::Error {
+    let response = error_response(StatusCode::BAD_REQUEST, "bad_request", &err.to_string());
+    InternalError::from_response(err, response).into()
+}
+
+// Serve the sensor's API using the given configuration
+pub async fn serve<S: Sensor>(config: S::Config) -> std::io::Result<()> {
+    set_sensor_model(S::MODEL);
+    let server = config.server().clone();
+    let info = web::Data::new(ServiceInfo::new(&config));
+    let sensor_config = web::Data::new(config.clone()); // For the sensor's own routes
+    let sampler = web::Data::new(Sampler::<S>::start(config)?);
+    if let Some(mqtt) = &server.mqtt {
+        mqtt::start(mqtt, &server, S::MODEL, sampler.subscribe())?;
+    }
+    let server_config = web::Data::new(server.clone()); // Read by the Admin extractor
+
+    HttpServer::new(move || {
+        App::new()
+            .wrap(Logger::default())
+            .app_data(sampler.clone())
+            .app_data(server_config.clone())
+            .app_data(info.clone())
+            .app_data(sensor_config.clone())
+            .app_data(web::JsonConfig::default().error_handler(json_error))
+            .route("/sensor_data", web::get().to(get_sensor_data::<S>))
+            .configure(stream::routes::<S>)
+            .configure(history::routes::<S>)
+            .configure(health::routes::<S>)
+            .configure(metrics::routes::<S>)
+            .configure(S::routes)
+    })
+    .bind((server.bind_address.as_str(), server.network_port))?
+    .run()
+    .await
+}
+
+// Entry point for a sensor binary: set up logging, load config.json and serve
+pub fn run<S: Sensor>() -> std::io::Result<()> {
+    env_logger::init_from_env(Env::default().default_filter_or("info"));
+
+    let config: S::Config = read_or_create_config();
+
+    actix_web::rt::System::new().block_on(serve::<S>(config))
+}
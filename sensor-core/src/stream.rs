@@ -0,0 +1,96 @@
+// /sensor_data/stream, pushes each new reading to the client as a Server-Sent Event
+
+use actix_web::web::{self, Bytes};
+use actix_web::HttpResponse;
+use futures_util::stream;
+use serde::Deserialize;
+use std::convert::Infallible;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::{error::RecvError, Receiver};
+use tokio::time::timeout;
+
+use crate::sampler::Sampler;
+use crate::sensor::Sensor;
+use crate::server::SensorData;
+
+// Comment line sent when there has been nothing else, so proxies keep the connection open
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+#[derive(Deserialize)]
+struct StreamQuery {
+    #[serde(default)]
+    min_interval_ms: u64, // Readings closer together than this are skipped
+}
+
+struct StreamState {
+    updates: Receiver<String>,
+    first: Option<String>, // Latest reading, sent straight away so the client has something to show
+    min_interval: Duration,
+    last_sent: Option<Instant>,
+}
+
+fn event(json: &str) -> Bytes {
+    Bytes::from(format!("data: {}\n\n", json))
+}
+
+pub(crate) fn routes<S: Sensor>(cfg: &mut web::ServiceConfig) {
+    cfg.route("/sensor_data/stream", web::get().to(sensor_data_stream::<S>));
+}
+
+async fn sensor_data_stream<S: Sensor>(
+    sampler: web::Data<Sampler<S>>,
+    query: web::Query<StreamQuery>,
+) -> HttpResponse {
+    let updates = sampler.subscribe();
+
+    let first = {
+        let state = sampler.state();
+        state
+            .latest
+            .as_ref()
+            .filter(|sample| sample.age() <= sampler.stale_after())
+            .and_then(|sample| {
+                serde_json::to_string(&SensorData {
+                    timestamp: sample.timestamp.to_rfc3339(),
+                    model: S::MODEL,
+                    age_ms: sample.age().as_millis() as u64,
+                    reading: &sample.reading,
+                })
+                .ok()
+            })
+    };
+
+    let state = StreamState {
+        updates,
+        first,
+        min_interval: Duration::from_millis(query.min_interval_ms),
+        last_sent: None,
+    };
+
+    let events = stream::unfold(state, |mut state| async move {
+        if let Some(first) = state.first.take() {
+            state.last_sent = Some(Instant::now());
+            return Some((Ok::<_, Infallible>(event(&first)), state));
+        }
+
+        loop {
+            match timeout(KEEP_ALIVE, state.updates.recv()).await {
+                Ok(Ok(json)) => {
+                    let due = state.last_sent.is_none_or(|sent| sent.elapsed() >= state.min_interval);
+                    if due {
+                        state.last_sent = Some(Instant::now());
+                        return Some((Ok(event(&json)), state));
+                    }
+                }
+                Ok(Err(RecvError::Lagged(_))) => continue, // Fell behind, carry on from the newest
+                Ok(Err(RecvError::Closed)) => return None,  // The sampler has gone
+                Err(_) => return Some((Ok(Bytes::from_static(b": keep-alive\n\n")), state)),
+            }
+        }
+    });
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(events)
+}
@@ -1,119 +1,128 @@
-use actix_web::{web, App, HttpServer, HttpResponse, Responder, middleware::Logger}; // Import necessary Actix Web components
-use tsl2591::{Driver, Gain, IntegrationTimes}; // Import TSL2591 sensor library and necessary enums
-use linux_embedded_hal::{I2cdev, Delay};  // Import I2C device and delay from linux_embedded_hal
+mod tsl2591;
+
+use linux_embedded_hal::I2cdev;  // Import I2C device from linux_embedded_hal
 use serde::{Deserialize, Serialize}; // Import serialization/deserialization from Serde
-use chrono::Utc; // Import Utc for timestamps
-use std::fs; // Import filesystem operations
-use std::fs::File; // Import file operations
-use std::io::Write; // Import write operations
-use env_logger::Env; // Import environment logger
+use sensor_core::{Sensor, SensorConfig, SensorError, ServerConfig}; // Import shared sensor server
+use tsl2591::{Gain, IntegrationTime, TSL2591}; // Import TSL2591 driver
 
 // Configuration structure for the application
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 struct Config {
-    network_port: u16, // Port for the web server
+    #[serde(flatten)]
+    server: ServerConfig, // Port, bind address and I2C bus path
     i2c_address_decimal: u16, // I2C address of the TSL2591 sensor
-    i2c_bus_device_path: String, // Path to the I2C bus device
-    bind_address: String, // Address to bind the web server to
+    #[serde(default = "default_gain")]
+    gain: Gain, // low, med, high or max, the starting point when auto_range is on
+    #[serde(default = "default_integration_time")]
+    integration_time_ms: IntegrationTime, // 100 to 600 in steps of 100
+    #[serde(default = "default_auto_range")]
+    auto_range: bool, // Step gain and integration time when counts saturate or are too low
+}
+
+fn default_gain() -> Gain {
+    Gain::Med
+}
+
+fn default_integration_time() -> IntegrationTime {
+    IntegrationTime::Ms100
+}
+
+fn default_auto_range() -> bool {
+    true
 }
 
 // Default implementation for the Config struct
 impl Default for Config {
     fn default() -> Self {
         Config {
-            network_port: 5004, // Default network port
+            server: ServerConfig::with_port(5004), // Default network port
             i2c_address_decimal: 0x29, // Default I2C address (41 in decimal)
-            i2c_bus_device_path: String::from("/dev/i2c-1"), // Default I2C bus device path
-            bind_address: String::from("0.0.0.0"), // Default bind address
+            gain: default_gain(),
+            integration_time_ms: default_integration_time(),
+            auto_range: default_auto_range(),
         }
     }
 }
 
-// Function to read the configuration from a file or create a default one if it doesn't exist
-fn read_or_create_config() -> Config {
-    let config_path = "config.json";
-    if let Ok(config_data) = fs::read_to_string(config_path) {
-        if let Ok(config) = serde_json::from_str(&config_data) {
-            return config;
-        }
+impl SensorConfig for Config {
+    fn server(&self) -> &ServerConfig {
+        &self.server
     }
 
-    let default_config = Config::default();
-    let config_data = serde_json::to_string_pretty(&default_config).unwrap();
-    let mut file = File::create(config_path).unwrap();
-    file.write_all(config_data.as_bytes()).unwrap();
-    default_config
+    fn i2c_address(&self) -> Option<u16> {
+        Some(self.i2c_address_decimal)
+    }
 }
 
 // Structure to hold sensor data
 #[derive(Serialize)]
 struct SensorData {
-    timestamp: String,
-    model: String,
-    luminosity: f32,
+    lux: f32,
+    full_spectrum: u16,
+    infrared: u16,
+    visible: u16,
+    saturated: bool,
+    gain: f32,
+    integration_time_ms: u16,
 }
 
-async fn get_sensor_data() -> impl Responder {
-    let config = read_or_create_config();
-
-    // Set up the I2C bus and TSL2591 sensor
-    let i2c_bus = match I2cdev::new(&config.i2c_bus_device_path) {
-        Ok(bus) => bus,
-        Err(_) => return HttpResponse::InternalServerError().body("Failed to open I2C bus"),
-    };
+struct Tsl2591Sensor {
+    tsl2591: TSL2591<I2cdev>,
+    auto_range: bool,
+}
 
-    // Create the delay object from linux_embedded_hal
-    let mut delay = Delay {};
+impl Sensor for Tsl2591Sensor {
+    type Config = Config;
+    type Reading = SensorData;
 
-    // Create TSL2591 sensor object with the correct I2C address
-    let mut tsl2591 = Driver::new(i2c_bus).unwrap();
+    const MODEL: &'static str = "TSL2591";
 
-    // Initialize the TSL2591 sensor with the delay
-    tsl2591.enable().unwrap();
+    fn init(config: &Config) -> Result<Self, SensorError> {
+        // Set up the I2C bus and TSL2591 sensor
+        let i2c_bus = I2cdev::new(&config.server.i2c_bus_device_path).map_err(SensorError::bus)?;
 
-    // Set gain and integration time
-    tsl2591.set_gain(Some(Gain::MED)).unwrap();
-    tsl2591.set_timing(Some(IntegrationTimes::_100MS)).unwrap();
+        // Create TSL2591 sensor object with the correct I2C address
+        let mut tsl2591 = TSL2591::new(i2c_bus, config.i2c_address_decimal as u8);
 
-    // Read sensor data
-    let (ch0, ch1) = match tsl2591.get_channel_data(&mut delay) {
-        Ok(data) => data,
-        Err(e) => {
-            eprintln!("Failed to read sensor data: {:?}", e);
-            return HttpResponse::InternalServerError().body("Failed to read sensor data");
-        }
-    };
+        // Check the device ID, set gain and integration time and power on
+        tsl2591.begin(config.gain, config.integration_time_ms)?;
 
-    let luminosity = match tsl2591.calculate_lux(ch0, ch1) {
-        Ok(lux) => lux,
-        Err(e) => {
-            eprintln!("Failed to calculate lux: {:?}", e);
-            return HttpResponse::InternalServerError().body("Failed to calculate lux");
-        }
-    };
+        Ok(Tsl2591Sensor {
+            tsl2591,
+            auto_range: config.auto_range,
+        })
+    }
 
-    // Create sensor data response
-    let sensor_data = SensorData {
-        timestamp: Utc::now().to_rfc3339(),
-        model: String::from("TSL2591"),
-        luminosity,
-    };
+    fn read(&mut self) -> Result<SensorData, SensorError> {
+        let measurement = if self.auto_range {
+            self.tsl2591.measure_auto_range()
+        } else {
+            self.tsl2591.measure()
+        }?;
+
+        Ok(SensorData {
+            lux: measurement.lux,
+            full_spectrum: measurement.full_spectrum,
+            infrared: measurement.infrared,
+            visible: measurement.visible(),
+            saturated: measurement.saturated,
+            gain: measurement.gain.factor(),
+            integration_time_ms: measurement.integration_time.ms(),
+        })
+    }
+}
 
-    HttpResponse::Ok().json(sensor_data)
+// Sort driver errors into the shared error codes
+impl<E: embedded_hal::i2c::Error> From<tsl2591::Error<E>> for SensorError {
+    fn from(e: tsl2591::Error<E>) -> Self {
+        match e {
+            tsl2591::Error::I2c(e) => SensorError::i2c(e),
+            tsl2591::Error::WrongDevice => SensorError::NotFound(String::from("ID register is not a TSL2591")),
+            tsl2591::Error::NotReady => SensorError::Timeout(String::from("No integration cycle finished in time")),
+        }
+    }
 }
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    env_logger::init_from_env(Env::default().default_filter_or("info"));
-
-    let config = read_or_create_config();
-
-    HttpServer::new(|| {
-        App::new()
-            .wrap(Logger::default())
-            .route("/sensor_data", web::get().to(get_sensor_data))
-    })
-    .bind((config.bind_address.as_str(), config.network_port))? // Use bind_address from config
-    .run()
-    .await
-}
\ No newline at end of file
+fn main() -> std::io::Result<()> {
+    sensor_core::run::<Tsl2591Sensor>()
+}
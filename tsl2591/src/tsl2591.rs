@@ -0,0 +1,488 @@
+use embedded_hal::i2c::I2c;
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use std::fmt::Debug;
+use std::time::{Duration, Instant};
+
+const COMMAND: u8 = 0xA0; // Command bit with normal register access
+
+const ENABLE: u8 = 0x00;
+const CONFIG: u8 = 0x01;
+const ID: u8 = 0x12;
+const STATUS: u8 = 0x13;
+const C0DATAL: u8 = 0x14; // Followed by C0DATAH, C1DATAL, C1DATAH
+
+const ENABLE_PON: u8 = 0x01; // Power on
+const ENABLE_AEN: u8 = 0x02; // ALS enable
+const STATUS_AVALID: u8 = 0x01; // An integration cycle finished since AEN was set
+const DEVICE_ID: u8 = 0x50;
+
+// Counts per lux scaling from the TSL2591 application note
+const LUX_DF: f32 = 408.0;
+
+// Auto-ranging steps up below this many full spectrum counts
+const AUTO_RANGE_LOW_COUNTS: u16 = 100;
+// and steps down above this fraction of the maximum count
+const AUTO_RANGE_HIGH_FRACTION: f32 = 0.9;
+// Gain/time changes tried per reading before giving up
+const AUTO_RANGE_MAX_STEPS: usize = 8;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Gain {
+    Low,  // 1x
+    Med,  // 25x
+    High, // 428x
+    Max,  // 9876x
+}
+
+impl Gain {
+    fn bits(self) -> u8 {
+        match self {
+            Gain::Low => 0x00,
+            Gain::Med => 0x10,
+            Gain::High => 0x20,
+            Gain::Max => 0x30,
+        }
+    }
+
+    pub fn factor(self) -> f32 {
+        match self {
+            Gain::Low => 1.0,
+            Gain::Med => 25.0,
+            Gain::High => 428.0,
+            Gain::Max => 9876.0,
+        }
+    }
+
+    fn higher(self) -> Option<Gain> {
+        match self {
+            Gain::Low => Some(Gain::Med),
+            Gain::Med => Some(Gain::High),
+            Gain::High => Some(Gain::Max),
+            Gain::Max => None,
+        }
+    }
+
+    fn lower(self) -> Option<Gain> {
+        match self {
+            Gain::Low => None,
+            Gain::Med => Some(Gain::Low),
+            Gain::High => Some(Gain::Med),
+            Gain::Max => Some(Gain::High),
+        }
+    }
+}
+
+// Stored in config.json as the number of milliseconds
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(try_from = "u16", into = "u16")]
+pub enum IntegrationTime {
+    Ms100,
+    Ms200,
+    Ms300,
+    Ms400,
+    Ms500,
+    Ms600,
+}
+
+impl IntegrationTime {
+    const ALL: [IntegrationTime; 6] = [
+        IntegrationTime::Ms100,
+        IntegrationTime::Ms200,
+        IntegrationTime::Ms300,
+        IntegrationTime::Ms400,
+        IntegrationTime::Ms500,
+        IntegrationTime::Ms600,
+    ];
+
+    fn bits(self) -> u8 {
+        self.index() as u8
+    }
+
+    fn index(self) -> usize {
+        IntegrationTime::ALL.iter().position(|&it| it == self).unwrap_or(0)
+    }
+
+    pub fn ms(self) -> u16 {
+        (self.index() as u16 + 1) * 100
+    }
+
+    // The 100 ms setting cannot reach the full 16 bit range
+    fn max_count(self) -> u16 {
+        match self {
+            IntegrationTime::Ms100 => 36863,
+            _ => 65535,
+        }
+    }
+
+    fn longer(self) -> Option<IntegrationTime> {
+        IntegrationTime::ALL.get(self.index() + 1).copied()
+    }
+
+    fn shorter(self) -> Option<IntegrationTime> {
+        self.index().checked_sub(1).map(|i| IntegrationTime::ALL[i])
+    }
+}
+
+impl TryFrom<u16> for IntegrationTime {
+    type Error = String;
+
+    fn try_from(ms: u16) -> Result<Self, String> {
+        match ms {
+            100 | 200 | 300 | 400 | 500 | 600 => Ok(IntegrationTime::ALL[(ms / 100 - 1) as usize]),
+            _ => Err(format!("integration time must be 100, 200, 300, 400, 500 or 600 ms, got {}", ms)),
+        }
+    }
+}
+
+impl From<IntegrationTime> for u16 {
+    fn from(it: IntegrationTime) -> u16 {
+        it.ms()
+    }
+}
+
+#[derive(Debug)]
+pub enum Error<E> {
+    I2c(E),
+    WrongDevice, // ID register did not read 0x50
+    NotReady,    // No integration cycle finished in time
+}
+
+impl<E> From<E> for Error<E> {
+    fn from(e: E) -> Self {
+        Error::I2c(e)
+    }
+}
+
+// Channel counts along with the settings they were taken with
+pub struct Measurement {
+    pub full_spectrum: u16, // Channel 0, visible and infrared
+    pub infrared: u16,      // Channel 1
+    pub lux: f32,
+    pub saturated: bool,
+    pub gain: Gain,
+    pub integration_time: IntegrationTime,
+}
+
+impl Measurement {
+    pub fn visible(&self) -> u16 {
+        self.full_spectrum.saturating_sub(self.infrared)
+    }
+}
+
+pub struct TSL2591<I2C> {
+    i2c: I2C,
+    address: u8,
+    gain: Gain,
+    integration_time: IntegrationTime,
+}
+
+impl<I2C, E> TSL2591<I2C>
+where
+    I2C: I2c<Error = E>,
+    E: Debug,
+{
+    pub fn new(i2c: I2C, address: u8) -> Self {
+        TSL2591 {
+            i2c,
+            address,
+            gain: Gain::Med,
+            integration_time: IntegrationTime::Ms100,
+        }
+    }
+
+    pub fn begin(&mut self, gain: Gain, integration_time: IntegrationTime) -> Result<(), Error<E>> {
+        info!("Initializing TSL2591 sensor");
+
+        let id = self.read_register(ID)?;
+        if id != DEVICE_ID {
+            error!("Invalid device ID: {:#X}", id);
+            return Err(Error::WrongDevice);
+        }
+
+        self.configure(gain, integration_time)?;
+
+        info!("TSL2591 sensor initialized with gain {:?} and {} ms integration", gain, integration_time.ms());
+        Ok(())
+    }
+
+    // Apply gain and integration time and restart integration so the next result uses them
+    pub fn configure(&mut self, gain: Gain, integration_time: IntegrationTime) -> Result<(), E> {
+        self.write_register(ENABLE, ENABLE_PON)?;
+        self.write_register(CONFIG, gain.bits() | integration_time.bits())?;
+        self.write_register(ENABLE, ENABLE_PON | ENABLE_AEN)?;
+        self.gain = gain;
+        self.integration_time = integration_time;
+        Ok(())
+    }
+
+    // Read both channels once an integration cycle with the current settings has finished
+    pub fn measure(&mut self) -> Result<Measurement, Error<E>> {
+        self.wait_for_data()?;
+
+        let mut buf = [0; 4];
+        self.i2c.write_read(self.address, &[COMMAND | C0DATAL], &mut buf)?;
+        let full_spectrum = u16::from_le_bytes([buf[0], buf[1]]);
+        let infrared = u16::from_le_bytes([buf[2], buf[3]]);
+
+        let max_count = self.integration_time.max_count();
+        let saturated = full_spectrum >= max_count || infrared >= max_count;
+
+        Ok(Measurement {
+            full_spectrum,
+            infrared,
+            lux: self.calculate_lux(full_spectrum, infrared),
+            saturated,
+            gain: self.gain,
+            integration_time: self.integration_time,
+        })
+    }
+
+    // Step gain, then integration time, until the counts are neither saturated nor too low
+    pub fn measure_auto_range(&mut self) -> Result<Measurement, Error<E>> {
+        let mut measurement = self.measure()?;
+
+        for _ in 0..AUTO_RANGE_MAX_STEPS {
+            let high = (self.integration_time.max_count() as f32 * AUTO_RANGE_HIGH_FRACTION) as u16;
+
+            let next = if measurement.saturated || measurement.full_spectrum > high {
+                match (self.gain.lower(), self.integration_time.shorter()) {
+                    (Some(gain), _) => (gain, self.integration_time),
+                    (None, Some(it)) => (self.gain, it),
+                    (None, None) => break,
+                }
+            } else if measurement.full_spectrum < AUTO_RANGE_LOW_COUNTS {
+                match (self.gain.higher(), self.integration_time.longer()) {
+                    (Some(gain), _) => (gain, self.integration_time),
+                    (None, Some(it)) => (self.gain, it),
+                    (None, None) => break,
+                }
+            } else {
+                break;
+            };
+
+            debug!(
+                "TSL2591 auto range: {} counts, switching to gain {:?} and {} ms",
+                measurement.full_spectrum,
+                next.0,
+                next.1.ms()
+            );
+            self.configure(next.0, next.1)?;
+            measurement = self.measure()?;
+        }
+
+        Ok(measurement)
+    }
+
+    // Lux from the application note, counts per lux scale with gain and integration time
+    fn calculate_lux(&self, full_spectrum: u16, infrared: u16) -> f32 {
+        if full_spectrum == 0 {
+            return 0.0;
+        }
+        let ch0 = full_spectrum as f32;
+        let ch1 = infrared as f32;
+        let cpl = (self.integration_time.ms() as f32 * self.gain.factor()) / LUX_DF;
+        ((ch0 - ch1) * (1.0 - ch1 / ch0) / cpl).max(0.0)
+    }
+
+    fn wait_for_data(&mut self) -> Result<(), Error<E>> {
+        let deadline = Instant::now() + Duration::from_millis(self.integration_time.ms() as u64 * 2 + 50);
+        while self.read_register(STATUS)? & STATUS_AVALID == 0 {
+            if Instant::now() > deadline {
+                return Err(Error::NotReady);
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        Ok(())
+    }
+
+    fn read_register(&mut self, reg: u8) -> Result<u8, E> {
+        let mut buf = [0];
+        self.i2c.write_read(self.address, &[COMMAND | reg], &mut buf)?;
+        Ok(buf[0])
+    }
+
+    fn write_register(&mut self, reg: u8, value: u8) -> Result<(), E> {
+        self.i2c.write(self.address, &[COMMAND | reg, value])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_hal::i2c::{ErrorType, Operation};
+    use std::convert::Infallible;
+
+    const ADDRESS: u8 = 0x29;
+    const GAINS: [Gain; 4] = [Gain::Low, Gain::Med, Gain::High, Gain::Max];
+
+    // A TSL2591 under steady light, given as full spectrum counts per unit of gain and
+    // millisecond. Counts follow the last gain and integration time written and clip at the
+    // integration time's maximum.
+    struct FakeBus {
+        light: f32,
+        infrared_fraction: f32,
+        config: u8,
+        pointer: u8,
+        configured: Vec<(Gain, IntegrationTime)>,
+    }
+
+    impl FakeBus {
+        fn settings(&self) -> (Gain, IntegrationTime) {
+            let gain = GAINS.into_iter().find(|gain| gain.bits() == self.config & 0x30).unwrap();
+            (gain, IntegrationTime::ALL[(self.config & 0x07) as usize])
+        }
+
+        fn channels(&self) -> [u8; 4] {
+            let (gain, integration_time) = self.settings();
+            let full = self.light * gain.factor() * integration_time.ms() as f32;
+            let count = |counts: f32| (counts.min(integration_time.max_count() as f32) as u16).to_le_bytes();
+            let [c0_low, c0_high] = count(full);
+            let [c1_low, c1_high] = count(full * self.infrared_fraction);
+            [c0_low, c0_high, c1_low, c1_high]
+        }
+    }
+
+    impl ErrorType for FakeBus {
+        type Error = Infallible;
+    }
+
+    impl I2c for FakeBus {
+        fn transaction(&mut self, address: u8, operations: &mut [Operation<'_>]) -> Result<(), Infallible> {
+            assert_eq!(address, ADDRESS);
+            for operation in operations {
+                match operation {
+                    Operation::Write([register]) => self.pointer = *register & !COMMAND,
+                    Operation::Write([register, value]) if *register == COMMAND | CONFIG => {
+                        self.config = *value;
+                        self.configured.push(self.settings());
+                    }
+                    Operation::Write([register, _]) => assert_eq!(*register, COMMAND | ENABLE),
+                    Operation::Write(bytes) => panic!("Unexpected write {:?}", bytes),
+                    Operation::Read(buffer) => match self.pointer {
+                        STATUS => buffer[0] = STATUS_AVALID,
+                        ID => buffer[0] = DEVICE_ID,
+                        C0DATAL => buffer.copy_from_slice(&self.channels()),
+                        register => panic!("Unexpected read of {:#04x}", register),
+                    },
+                }
+            }
+            Ok(())
+        }
+    }
+
+    // A sensor left on the given settings by its last reading
+    fn tsl2591(light: f32, gain: Gain, integration_time: IntegrationTime) -> TSL2591<FakeBus> {
+        let bus = FakeBus {
+            light,
+            infrared_fraction: 0.2,
+            config: gain.bits() | integration_time.bits(),
+            pointer: 0,
+            configured: Vec::new(),
+        };
+        TSL2591 { i2c: bus, address: ADDRESS, gain, integration_time }
+    }
+
+    #[test]
+    fn lux_from_the_application_note() {
+        // 25x and 100 ms is 2500 / 408 counts per lux, (1000 - 200) * (1 - 200 / 1000) / 6.127
+        let sensor = tsl2591(0.0, Gain::Med, IntegrationTime::Ms100);
+        assert!((sensor.calculate_lux(1000, 200) - 104.45).abs() < 0.01);
+
+        // The same light at 4x the gain and time gives the same lux
+        let sensor = tsl2591(0.0, Gain::Low, IntegrationTime::Ms100);
+        assert!((sensor.calculate_lux(40, 8) - 104.45).abs() < 0.01);
+    }
+
+    #[test]
+    fn lux_of_infrared_only_is_zero() {
+        let sensor = tsl2591(0.0, Gain::Med, IntegrationTime::Ms100);
+        assert_eq!(sensor.calculate_lux(0, 0), 0.0);
+        assert_eq!(sensor.calculate_lux(150, 150), 0.0);
+    }
+
+    #[test]
+    fn saturation_depends_on_integration_time() {
+        // 100 ms tops out at 36863 counts, longer times at 65535
+        let mut sensor = tsl2591(36863.0 / 100.0, Gain::Low, IntegrationTime::Ms100);
+        assert!(sensor.measure().unwrap().saturated);
+        let mut sensor = tsl2591(36862.0 / 100.0, Gain::Low, IntegrationTime::Ms100);
+        assert!(!sensor.measure().unwrap().saturated);
+
+        let mut sensor = tsl2591(36863.0 / 200.0, Gain::Low, IntegrationTime::Ms200);
+        assert!(!sensor.measure().unwrap().saturated);
+        let mut sensor = tsl2591(100_000.0, Gain::Low, IntegrationTime::Ms600);
+        assert!(sensor.measure().unwrap().saturated);
+    }
+
+    #[test]
+    fn integration_time_from_milliseconds() {
+        for integration_time in IntegrationTime::ALL {
+            assert_eq!(IntegrationTime::try_from(integration_time.ms()), Ok(integration_time));
+        }
+        assert!(IntegrationTime::try_from(0).is_err());
+        assert!(IntegrationTime::try_from(150).is_err());
+        assert!(IntegrationTime::try_from(700).is_err());
+    }
+
+    #[test]
+    fn auto_range_keeps_settings_in_range() {
+        let mut sensor = tsl2591(5.0, Gain::Med, IntegrationTime::Ms100);
+        let measurement = sensor.measure_auto_range().unwrap();
+
+        assert!(sensor.i2c.configured.is_empty());
+        assert_eq!(measurement.full_spectrum, 12500);
+    }
+
+    #[test]
+    fn auto_range_lowers_gain_while_saturated() {
+        // Saturated at 9876x and 428x, 12500 counts at 25x
+        let mut sensor = tsl2591(5.0, Gain::Max, IntegrationTime::Ms100);
+        let measurement = sensor.measure_auto_range().unwrap();
+
+        assert_eq!(sensor.i2c.configured, [(Gain::High, IntegrationTime::Ms100), (Gain::Med, IntegrationTime::Ms100)]);
+        assert!(!measurement.saturated);
+        assert_eq!(measurement.full_spectrum, 12500);
+    }
+
+    #[test]
+    fn auto_range_shortens_integration_at_the_lowest_gain() {
+        // 60000 counts is over 90% of 65535 at 600 ms, 50000 at 500 ms isn't
+        let mut sensor = tsl2591(100.0, Gain::Low, IntegrationTime::Ms600);
+        let measurement = sensor.measure_auto_range().unwrap();
+
+        assert_eq!(sensor.i2c.configured, [(Gain::Low, IntegrationTime::Ms500)]);
+        assert_eq!(measurement.full_spectrum, 50000);
+    }
+
+    #[test]
+    fn auto_range_raises_gain_in_dim_light() {
+        // 50 counts at 1x, 1250 at 25x
+        let mut sensor = tsl2591(0.5, Gain::Low, IntegrationTime::Ms100);
+        let measurement = sensor.measure_auto_range().unwrap();
+
+        assert_eq!(sensor.i2c.configured, [(Gain::Med, IntegrationTime::Ms100)]);
+        assert_eq!(measurement.full_spectrum, 1250);
+    }
+
+    #[test]
+    fn auto_range_lengthens_integration_at_the_highest_gain() {
+        // 98 counts at 9876x and 100 ms, 196 at 200 ms
+        let mut sensor = tsl2591(98.0 / 987_600.0, Gain::Max, IntegrationTime::Ms100);
+        let measurement = sensor.measure_auto_range().unwrap();
+
+        assert_eq!(sensor.i2c.configured, [(Gain::Max, IntegrationTime::Ms200)]);
+        assert_eq!(measurement.gain, Gain::Max);
+        assert_eq!(measurement.integration_time, IntegrationTime::Ms200);
+    }
+
+    #[test]
+    fn auto_range_stops_at_the_most_sensitive_setting() {
+        let mut sensor = tsl2591(0.0, Gain::Max, IntegrationTime::Ms600);
+        let measurement = sensor.measure_auto_range().unwrap();
+
+        assert!(sensor.i2c.configured.is_empty());
+        assert_eq!(measurement.lux, 0.0);
+    }
+}
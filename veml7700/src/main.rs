@@ -1,3 +1,101 @@
-fn main() {
-    println!("Hello, world!");
+mod veml7700;
+
+use linux_embedded_hal::I2cdev;  // Import I2C device from linux_embedded_hal
+use serde::{Deserialize, Serialize}; // Import serialization/deserialization from Serde
+use sensor_core::{Sensor, SensorConfig, SensorError, ServerConfig}; // Import shared sensor server
+use veml7700::{Gain, IntegrationTime, VEML7700}; // Import VEML7700 driver
+
+// Configuration structure for the application
+#[derive(Serialize, Deserialize, Clone)]
+struct Config {
+    #[serde(flatten)]
+    server: ServerConfig, // Port, bind address and I2C bus path
+    i2c_address_decimal: u16, // I2C address of the VEML7700 sensor
+    gain: Gain, // x1/8, x1/4, x1 or x2, ignored when auto_range is on
+    integration_time_ms: IntegrationTime, // 25, 50, 100, 200, 400 or 800, ignored when auto_range is on
+    auto_range: bool, // Pick gain and integration time for the light level on every reading
+}
+
+// Default implementation for the Config struct
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            server: ServerConfig::with_port(5006), // Default network port
+            i2c_address_decimal: 0x10, // Default I2C address (16 in decimal)
+            gain: Gain::X1_8, // Lowest gain avoids saturating in daylight
+            integration_time_ms: IntegrationTime::Ms100,
+            auto_range: true,
+        }
+    }
+}
+
+impl SensorConfig for Config {
+    fn server(&self) -> &ServerConfig {
+        &self.server
+    }
+
+    fn i2c_address(&self) -> Option<u16> {
+        Some(self.i2c_address_decimal)
+    }
+}
+
+// Structure to hold sensor data
+#[derive(Serialize)]
+struct SensorData {
+    lux: f32,
+    white: f32,
+    raw_als: u16,
+    raw_white: u16,
+    gain: f32,
+    integration_time_ms: u16,
+}
+
+struct Veml7700Sensor {
+    veml7700: VEML7700<I2cdev>,
+    auto_range: bool,
+}
+
+impl Sensor for Veml7700Sensor {
+    type Config = Config;
+    type Reading = SensorData;
+
+    const MODEL: &'static str = "VEML7700";
+
+    fn init(config: &Config) -> Result<Self, SensorError> {
+        // Set up the I2C bus and VEML7700 sensor
+        let i2c_bus = I2cdev::new(&config.server.i2c_bus_device_path).map_err(SensorError::bus)?;
+
+        // Create VEML7700 sensor object with the correct I2C address
+        let mut veml7700 = VEML7700::new(i2c_bus, config.i2c_address_decimal as u8);
+
+        // Power on with the configured gain and integration time
+        veml7700.begin(config.gain, config.integration_time_ms).map_err(SensorError::i2c)?;
+
+        Ok(Veml7700Sensor {
+            veml7700,
+            auto_range: config.auto_range,
+        })
+    }
+
+    fn read(&mut self) -> Result<SensorData, SensorError> {
+        let measurement = if self.auto_range {
+            self.veml7700.measure_auto_range()
+        } else {
+            self.veml7700.measure()
+        }
+        .map_err(SensorError::i2c)?;
+
+        Ok(SensorData {
+            lux: measurement.lux,
+            white: measurement.white,
+            raw_als: measurement.raw_als,
+            raw_white: measurement.raw_white,
+            gain: measurement.gain.factor(),
+            integration_time_ms: measurement.integration_time.ms(),
+        })
+    }
+}
+
+fn main() -> std::io::Result<()> {
+    sensor_core::run::<Veml7700Sensor>()
 }
@@ -0,0 +1,445 @@
+use embedded_hal::i2c::I2c;
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use std::fmt::Debug;
+use std::time::Duration;
+
+const ALS_CONF_0: u8 = 0x00;
+const ALS: u8 = 0x04;
+const WHITE: u8 = 0x05;
+
+const ALS_SD: u16 = 0x0001; // Shut down bit in ALS_CONF_0
+
+// Lux per count at gain x2 and 800 ms, everything else scales from this
+const MAX_RESOLUTION: f32 = 0.0036;
+
+// Above this the response is no longer linear and needs the application note correction
+const CORRECTION_THRESHOLD_LUX: f32 = 1000.0;
+
+// Counts the auto-ranging tries to stay between
+const AUTO_RANGE_LOW_COUNTS: u16 = 100;
+const AUTO_RANGE_HIGH_COUNTS: u16 = 10000;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Gain {
+    #[serde(rename = "x1/8")]
+    X1_8,
+    #[serde(rename = "x1/4")]
+    X1_4,
+    #[serde(rename = "x1")]
+    X1,
+    #[serde(rename = "x2")]
+    X2,
+}
+
+impl Gain {
+    fn bits(self) -> u16 {
+        match self {
+            Gain::X1 => 0b00,
+            Gain::X2 => 0b01,
+            Gain::X1_8 => 0b10,
+            Gain::X1_4 => 0b11,
+        }
+    }
+
+    pub fn factor(self) -> f32 {
+        match self {
+            Gain::X1_8 => 0.125,
+            Gain::X1_4 => 0.25,
+            Gain::X1 => 1.0,
+            Gain::X2 => 2.0,
+        }
+    }
+
+    fn higher(self) -> Option<Gain> {
+        match self {
+            Gain::X1_8 => Some(Gain::X1_4),
+            Gain::X1_4 => Some(Gain::X1),
+            Gain::X1 => Some(Gain::X2),
+            Gain::X2 => None,
+        }
+    }
+}
+
+// Stored in config.json as the number of milliseconds
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(try_from = "u16", into = "u16")]
+pub enum IntegrationTime {
+    Ms25,
+    Ms50,
+    Ms100,
+    Ms200,
+    Ms400,
+    Ms800,
+}
+
+impl IntegrationTime {
+    fn bits(self) -> u16 {
+        match self {
+            IntegrationTime::Ms25 => 0b1100,
+            IntegrationTime::Ms50 => 0b1000,
+            IntegrationTime::Ms100 => 0b0000,
+            IntegrationTime::Ms200 => 0b0001,
+            IntegrationTime::Ms400 => 0b0010,
+            IntegrationTime::Ms800 => 0b0011,
+        }
+    }
+
+    pub fn ms(self) -> u16 {
+        match self {
+            IntegrationTime::Ms25 => 25,
+            IntegrationTime::Ms50 => 50,
+            IntegrationTime::Ms100 => 100,
+            IntegrationTime::Ms200 => 200,
+            IntegrationTime::Ms400 => 400,
+            IntegrationTime::Ms800 => 800,
+        }
+    }
+
+    fn longer(self) -> Option<IntegrationTime> {
+        match self {
+            IntegrationTime::Ms25 => Some(IntegrationTime::Ms50),
+            IntegrationTime::Ms50 => Some(IntegrationTime::Ms100),
+            IntegrationTime::Ms100 => Some(IntegrationTime::Ms200),
+            IntegrationTime::Ms200 => Some(IntegrationTime::Ms400),
+            IntegrationTime::Ms400 => Some(IntegrationTime::Ms800),
+            IntegrationTime::Ms800 => None,
+        }
+    }
+
+    fn shorter(self) -> Option<IntegrationTime> {
+        match self {
+            IntegrationTime::Ms25 => None,
+            IntegrationTime::Ms50 => Some(IntegrationTime::Ms25),
+            IntegrationTime::Ms100 => Some(IntegrationTime::Ms50),
+            IntegrationTime::Ms200 => Some(IntegrationTime::Ms100),
+            IntegrationTime::Ms400 => Some(IntegrationTime::Ms200),
+            IntegrationTime::Ms800 => Some(IntegrationTime::Ms400),
+        }
+    }
+}
+
+impl TryFrom<u16> for IntegrationTime {
+    type Error = String;
+
+    fn try_from(ms: u16) -> Result<Self, String> {
+        match ms {
+            25 => Ok(IntegrationTime::Ms25),
+            50 => Ok(IntegrationTime::Ms50),
+            100 => Ok(IntegrationTime::Ms100),
+            200 => Ok(IntegrationTime::Ms200),
+            400 => Ok(IntegrationTime::Ms400),
+            800 => Ok(IntegrationTime::Ms800),
+            _ => Err(format!("integration time must be 25, 50, 100, 200, 400 or 800 ms, got {}", ms)),
+        }
+    }
+}
+
+impl From<IntegrationTime> for u16 {
+    fn from(it: IntegrationTime) -> u16 {
+        it.ms()
+    }
+}
+
+// A reading along with the settings it was taken with
+pub struct Measurement {
+    pub raw_als: u16,
+    pub raw_white: u16,
+    pub lux: f32,
+    pub white: f32,
+    pub gain: Gain,
+    pub integration_time: IntegrationTime,
+}
+
+pub struct VEML7700<I2C> {
+    i2c: I2C,
+    address: u8,
+    gain: Gain,
+    integration_time: IntegrationTime,
+}
+
+impl<I2C, E> VEML7700<I2C>
+where
+    I2C: I2c<Error = E>,
+    E: Debug,
+{
+    pub fn new(i2c: I2C, address: u8) -> Self {
+        VEML7700 {
+            i2c,
+            address,
+            gain: Gain::X1_8,
+            integration_time: IntegrationTime::Ms100,
+        }
+    }
+
+    pub fn begin(&mut self, gain: Gain, integration_time: IntegrationTime) -> Result<(), E> {
+        info!("Initializing VEML7700 sensor");
+        self.configure(gain, integration_time)?;
+        info!("VEML7700 sensor initialized with gain {:?} and {} ms integration", gain, integration_time.ms());
+        Ok(())
+    }
+
+    // Write gain and integration time with the sensor powered on, then wait for a clean sample
+    pub fn configure(&mut self, gain: Gain, integration_time: IntegrationTime) -> Result<(), E> {
+        let conf = (gain.bits() << 11) | (integration_time.bits() << 6);
+        self.write_register(ALS_CONF_0, conf & !ALS_SD)?;
+        self.gain = gain;
+        self.integration_time = integration_time;
+
+        // The cycle in progress still uses the old settings, so skip it
+        std::thread::sleep(Duration::from_millis(2 * integration_time.ms() as u64 + 5));
+        Ok(())
+    }
+
+    pub fn read_als_raw(&mut self) -> Result<u16, E> {
+        self.read_register(ALS)
+    }
+
+    pub fn read_white_raw(&mut self) -> Result<u16, E> {
+        self.read_register(WHITE)
+    }
+
+    // Read both channels with the current gain and integration time
+    pub fn measure(&mut self) -> Result<Measurement, E> {
+        let raw_als = self.read_als_raw()?;
+        let raw_white = self.read_white_raw()?;
+        let resolution = self.resolution();
+
+        Ok(Measurement {
+            raw_als,
+            raw_white,
+            lux: correct_lux(raw_als as f32 * resolution),
+            white: raw_white as f32 * resolution,
+            gain: self.gain,
+            integration_time: self.integration_time,
+        })
+    }
+
+    // Adjust gain and integration time to the current light level following the Vishay
+    // application note, then measure. Each step costs two integration cycles, so it carries
+    // on from the settings the last reading settled on and only steps when the count leaves
+    // the range
+    pub fn measure_auto_range(&mut self) -> Result<Measurement, E> {
+        let mut raw = self.read_als_raw()?;
+
+        if raw > AUTO_RANGE_HIGH_COUNTS && (self.gain != Gain::X1_8 || self.integration_time.ms() > 100) {
+            // Left sensitive by a dark spell, start again where the application note does
+            self.configure(Gain::X1_8, IntegrationTime::Ms100)?;
+            raw = self.read_als_raw()?;
+        }
+
+        if raw <= AUTO_RANGE_LOW_COUNTS {
+            // Too dark, raise gain first and then integration time
+            while raw <= AUTO_RANGE_LOW_COUNTS {
+                let (gain, integration_time) = match (self.gain.higher(), self.integration_time.longer()) {
+                    (Some(higher), _) => (higher, self.integration_time),
+                    (None, Some(longer)) => (self.gain, longer),
+                    (None, None) => break,
+                };
+                self.configure(gain, integration_time)?;
+                raw = self.read_als_raw()?;
+            }
+        } else {
+            // Bright, shorten integration time to keep away from saturation
+            while raw > AUTO_RANGE_HIGH_COUNTS {
+                match self.integration_time.shorter() {
+                    Some(shorter) => self.configure(self.gain, shorter)?,
+                    None => break,
+                }
+                raw = self.read_als_raw()?;
+            }
+        }
+
+        debug!(
+            "VEML7700 auto range on gain {:?}, {} ms, {} counts",
+            self.gain,
+            self.integration_time.ms(),
+            raw
+        );
+        self.measure()
+    }
+
+    // Lux per count for the current settings
+    fn resolution(&self) -> f32 {
+        MAX_RESOLUTION * (800.0 / self.integration_time.ms() as f32) * (2.0 / self.gain.factor())
+    }
+
+    fn read_register(&mut self, reg: u8) -> Result<u16, E> {
+        let mut buf = [0; 2];
+        self.i2c.write_read(self.address, &[reg], &mut buf)?;
+        Ok(u16::from_le_bytes(buf))
+    }
+
+    fn write_register(&mut self, reg: u8, value: u16) -> Result<(), E> {
+        let [low, high] = value.to_le_bytes();
+        self.i2c.write(self.address, &[reg, low, high])
+    }
+}
+
+// Non-linear correction from the VEML7700 application note for high illuminance
+fn correct_lux(lux: f32) -> f32 {
+    if lux <= CORRECTION_THRESHOLD_LUX {
+        return lux;
+    }
+    let lux = lux as f64;
+    (6.0135e-13 * lux.powi(4) - 9.3924e-9 * lux.powi(3) + 8.1488e-5 * lux.powi(2) + 1.0023 * lux) as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_hal::i2c::{ErrorType, Operation};
+    use std::convert::Infallible;
+
+    const ADDRESS: u8 = 0x10;
+
+    const GAINS: [Gain; 4] = [Gain::X1_8, Gain::X1_4, Gain::X1, Gain::X2];
+    const INTEGRATION_TIMES: [IntegrationTime; 6] = [
+        IntegrationTime::Ms25,
+        IntegrationTime::Ms50,
+        IntegrationTime::Ms100,
+        IntegrationTime::Ms200,
+        IntegrationTime::Ms400,
+        IntegrationTime::Ms800,
+    ];
+
+    // A VEML7700 under a fixed light level, counts follow whatever gain and integration time
+    // were last written
+    struct FakeBus {
+        lux: f32,
+        conf: u16,
+        pointer: u8,
+        configured: Vec<(Gain, IntegrationTime)>,
+    }
+
+    impl FakeBus {
+        fn settings(&self) -> (Gain, IntegrationTime) {
+            let gain = GAINS.into_iter().find(|gain| gain.bits() == (self.conf >> 11) & 0b11);
+            let integration_time = INTEGRATION_TIMES.into_iter().find(|it| it.bits() == (self.conf >> 6) & 0b1111);
+            (gain.unwrap(), integration_time.unwrap())
+        }
+
+        fn counts(&self) -> u16 {
+            let (gain, integration_time) = self.settings();
+            let resolution = MAX_RESOLUTION * (800.0 / integration_time.ms() as f32) * (2.0 / gain.factor());
+            (self.lux / resolution).min(u16::MAX as f32) as u16
+        }
+    }
+
+    impl ErrorType for FakeBus {
+        type Error = Infallible;
+    }
+
+    impl I2c for FakeBus {
+        fn transaction(&mut self, address: u8, operations: &mut [Operation<'_>]) -> Result<(), Infallible> {
+            assert_eq!(address, ADDRESS);
+            for operation in operations {
+                match operation {
+                    Operation::Write([register]) => self.pointer = *register,
+                    Operation::Write([ALS_CONF_0, low, high]) => {
+                        self.conf = u16::from_le_bytes([*low, *high]);
+                        self.configured.push(self.settings());
+                    }
+                    Operation::Write(bytes) => panic!("Unexpected write {:?}", bytes),
+                    Operation::Read(buffer) => {
+                        assert!(self.pointer == ALS || self.pointer == WHITE);
+                        buffer.copy_from_slice(&self.counts().to_le_bytes());
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
+
+    // A sensor that settled on the given settings on its last reading
+    fn veml7700(lux: f32, gain: Gain, integration_time: IntegrationTime) -> VEML7700<FakeBus> {
+        let conf = (gain.bits() << 11) | (integration_time.bits() << 6);
+        let bus = FakeBus { lux, conf, pointer: 0, configured: Vec::new() };
+        VEML7700 { i2c: bus, address: ADDRESS, gain, integration_time }
+    }
+
+    #[test]
+    fn lux_is_only_corrected_above_1000() {
+        assert_eq!(correct_lux(0.0), 0.0);
+        assert_eq!(correct_lux(500.0), 500.0);
+        assert_eq!(correct_lux(1000.0), 1000.0);
+        // 6.0135e-13 * 2000^4 - 9.3924e-9 * 2000^3 + 8.1488e-5 * 2000^2 + 1.0023 * 2000
+        assert!((correct_lux(2000.0) - 2265.03).abs() < 0.01, "{}", correct_lux(2000.0));
+        assert!(correct_lux(50000.0) > 50000.0);
+    }
+
+    #[test]
+    fn gain_steps_up_to_x2() {
+        assert_eq!(Gain::X1_8.higher(), Some(Gain::X1_4));
+        assert_eq!(Gain::X1_4.higher(), Some(Gain::X1));
+        assert_eq!(Gain::X1.higher(), Some(Gain::X2));
+        assert_eq!(Gain::X2.higher(), None);
+    }
+
+    #[test]
+    fn integration_time_steps_both_ways() {
+        for pair in INTEGRATION_TIMES.windows(2) {
+            assert_eq!(pair[0].longer(), Some(pair[1]));
+            assert_eq!(pair[1].shorter(), Some(pair[0]));
+        }
+        assert_eq!(IntegrationTime::Ms25.shorter(), None);
+        assert_eq!(IntegrationTime::Ms800.longer(), None);
+    }
+
+    #[test]
+    fn integration_time_from_milliseconds() {
+        for integration_time in INTEGRATION_TIMES {
+            assert_eq!(IntegrationTime::try_from(integration_time.ms()), Ok(integration_time));
+        }
+        for ms in [0, 24, 75, 1000] {
+            assert!(IntegrationTime::try_from(ms).is_err(), "{} ms", ms);
+        }
+    }
+
+    #[test]
+    fn resolution_scales_from_the_datasheet_maximum() {
+        assert_eq!(veml7700(0.0, Gain::X2, IntegrationTime::Ms800).resolution(), 0.0036);
+        assert_eq!(veml7700(0.0, Gain::X1, IntegrationTime::Ms100).resolution(), 0.0576);
+        assert!((veml7700(0.0, Gain::X1_8, IntegrationTime::Ms25).resolution() - 1.8432).abs() < 1e-6);
+    }
+
+    #[test]
+    fn settled_range_is_kept_without_reconfiguring() {
+        let mut veml7700 = veml7700(40.0, Gain::X2, IntegrationTime::Ms200);
+        let measurement = veml7700.measure_auto_range().unwrap();
+
+        assert!(veml7700.i2c.configured.is_empty());
+        assert_eq!((measurement.gain, measurement.integration_time), (Gain::X2, IntegrationTime::Ms200));
+        assert!((measurement.lux - 40.0).abs() < 0.1, "{}", measurement.lux);
+    }
+
+    #[test]
+    fn dimmer_light_steps_up_from_the_last_settings() {
+        // 3 lx is 13 counts at x1/4, 52 at x1 and 104 at x2
+        let mut veml7700 = veml7700(3.0, Gain::X1_4, IntegrationTime::Ms100);
+        let measurement = veml7700.measure_auto_range().unwrap();
+
+        assert_eq!(veml7700.i2c.configured, [(Gain::X1, IntegrationTime::Ms100), (Gain::X2, IntegrationTime::Ms100)]);
+        assert_eq!(measurement.gain, Gain::X2);
+        assert!((measurement.lux - 3.0).abs() < 0.1, "{}", measurement.lux);
+    }
+
+    #[test]
+    fn bright_light_after_a_dark_spell_starts_again_at_the_lowest_gain() {
+        // 6000 lx saturates x2, and is 13020 counts at x1/8 and 100 ms, 6510 at 50 ms
+        let mut veml7700 = veml7700(6000.0, Gain::X2, IntegrationTime::Ms100);
+        let measurement = veml7700.measure_auto_range().unwrap();
+
+        let configured = [(Gain::X1_8, IntegrationTime::Ms100), (Gain::X1_8, IntegrationTime::Ms50)];
+        assert_eq!(veml7700.i2c.configured, configured);
+        assert_eq!(measurement.raw_als, 6510);
+    }
+
+    #[test]
+    fn brighter_light_shortens_the_integration_time() {
+        let mut veml7700 = veml7700(6000.0, Gain::X1_8, IntegrationTime::Ms100);
+        veml7700.measure_auto_range().unwrap();
+
+        assert_eq!(veml7700.i2c.configured, [(Gain::X1_8, IntegrationTime::Ms50)]);
+    }
+}